@@ -0,0 +1,229 @@
+use crate::database::{self, Database};
+use crate::minhttpd::{HttpBody, HttpHandler, HttpHeaders, HttpParams, HttpResponse, HttpUri};
+use crate::Hit;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::fmt::Display;
+use std::str::FromStr;
+use std::time::{Instant, UNIX_EPOCH};
+
+/// Bumped on incompatible changes.
+pub const API_VERSION: u32 = 1;
+pub const OPENAPI: &str = include_str!("openapi.json");
+/// Every result reads its file's metadata.
+pub const MAX_LIMIT: usize = 200;
+
+#[derive(Debug)]
+pub struct ApiError {
+    pub status: u16,
+    pub message: String,
+}
+
+impl ApiError {
+    pub fn new(status: u16, message: impl Into<String>) -> Self {
+        Self {
+            status,
+            message: message.into(),
+        }
+    }
+
+    pub fn internal(error: impl Display) -> Self {
+        Self::new(500, error.to_string())
+    }
+
+    pub fn into_response(self) -> HttpResponse {
+        let body = serde_json::json!({
+            "error": { "code": self.status, "message": self.message }
+        });
+        HttpResponse::builder()
+            .set_code(self.status)
+            .add_header("Content-Type", "application/json")
+            .set_payload(body.to_string())
+            .build()
+    }
+}
+
+// anything failing inside a handler that was not turned into an `ApiError`
+// on purpose is a server error
+impl<E: std::error::Error> From<E> for ApiError {
+    fn from(error: E) -> Self {
+        Self::internal(error)
+    }
+}
+
+pub type ApiResult = Result<HttpResponse, ApiError>;
+
+pub fn handler<F>(f: F) -> HttpHandler
+where
+    F: Fn(HttpUri, HttpHeaders, HttpParams, HttpBody) -> ApiResult + Send + Sync + 'static,
+{
+    Box::new(move |uri, headers, params, body| {
+        Ok(f(uri.clone(), headers, params, body).unwrap_or_else(|e| {
+            // other 5xx statuses, like 501 for what a platform cannot do,
+            // are raised on purpose with a message meant for the client
+            if e.status == 500 {
+                // the details are only logged, they may tell paths and internals
+                log::error!("error handling {}: {}", uri, e.message);
+                return ApiError::new(e.status, "internal server error").into_response();
+            }
+            e.into_response()
+        }))
+    })
+}
+
+pub fn required<'a>(params: &'a HttpParams, name: &str) -> Result<&'a str, ApiError> {
+    params
+        .get(name)
+        .map(|value| value.trim())
+        .ok_or_else(|| ApiError::new(400, format!("missing parameter '{}'", name)))
+}
+
+pub fn optional<T: FromStr>(params: &HttpParams, name: &str) -> Result<Option<T>, ApiError> {
+    params
+        .get(name)
+        .map(|value| {
+            value
+                .trim()
+                .parse()
+                .map_err(|_| ApiError::new(400, format!("invalid parameter '{}'", name)))
+        })
+        .transpose()
+}
+
+pub fn limit(params: &HttpParams, default: usize) -> Result<usize, ApiError> {
+    match optional(params, "limit")? {
+        Some(limit) if limit > MAX_LIMIT => Err(ApiError::new(
+            400,
+            format!("parameter 'limit' may be at most {}", MAX_LIMIT),
+        )),
+        limit => Ok(limit.unwrap_or(default)),
+    }
+}
+
+/// Browsers cannot send a JSON content type to another site without a CORS
+/// preflight, which is never granted, so requiring it keeps other pages out.
+pub fn json_body<T: DeserializeOwned>(
+    headers: &HttpHeaders,
+    body: &HttpBody,
+) -> Result<T, ApiError> {
+    let is_json = headers
+        .get("content-type")
+        .is_some_and(|content_type| content_type.starts_with("application/json"));
+    if !is_json {
+        return Err(ApiError::new(415, "expected an application/json body"));
+    }
+    let body = body.as_deref().filter(|body| !body.is_empty());
+    serde_json::from_slice(body.unwrap_or(b"{}"))
+        .map_err(|e| ApiError::new(400, format!("invalid request body: {}", e)))
+}
+
+pub fn json_response(body: &impl Serialize) -> ApiResult {
+    Ok(HttpResponse::builder()
+        .set_code(200)
+        .add_header("Content-Type", "application/json")
+        .set_payload(serde_json::to_string(body)?)
+        .build())
+}
+
+pub fn took_ms(started: Instant) -> u64 {
+    started.elapsed().as_millis() as u64
+}
+
+#[derive(Serialize)]
+pub struct ResultItem<'a> {
+    pub id: String,
+    pub collection: &'a str,
+    pub path: &'a str,
+    /// Missing from listings that are not ranked, such as favourites.
+    pub score: Option<f32>,
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    pub size: Option<u64>,
+    /// Seconds since the Unix epoch.
+    pub mtime: Option<u64>,
+    pub tags: Vec<&'a str>,
+}
+
+impl<'a> ResultItem<'a> {
+    pub fn new(
+        collection: &'a str,
+        database: &'a Database,
+        path: &'a str,
+        score: Option<f32>,
+    ) -> Self {
+        let metadata = std::fs::metadata(database::key_path(path)).ok();
+        let (width, height) = crate::dupes::image_dimensions(path).unzip();
+        Self {
+            id: database::image_id(path),
+            collection,
+            path,
+            score,
+            width,
+            height,
+            size: metadata.as_ref().map(|m| m.len()),
+            mtime: metadata
+                .and_then(|m| m.modified().ok())
+                .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+                .map(|d| d.as_secs()),
+            tags: database.tags.get(path).map_or_else(Vec::new, |tags| {
+                tags.iter().map(|(t, _)| t.as_str()).collect()
+            }),
+        }
+    }
+}
+
+/// `total` counts all matches before the limit.
+#[derive(Serialize)]
+pub struct ResultPage<'a> {
+    pub version: u32,
+    pub results: Vec<ResultItem<'a>>,
+    pub total: usize,
+    pub took_ms: u64,
+}
+
+pub fn results_response(items: &[Hit], total: usize, started: Instant) -> ApiResult {
+    let results = items
+        .iter()
+        .map(|hit| ResultItem::new(hit.collection, hit.database, hit.path, Some(hit.score)))
+        .collect();
+    json_response(&ResultPage {
+        version: API_VERSION,
+        results,
+        total,
+        took_ms: took_ms(started),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_errors() {
+        let mut params = HttpParams::new();
+        params.insert("limit".to_string(), "ten".to_string());
+        let error = optional::<usize>(&params, "limit").unwrap_err();
+        assert_eq!(error.status, 400);
+        assert_eq!(required(&params, "text").unwrap_err().status, 400);
+        params.insert("limit".to_string(), "201".to_string());
+        assert_eq!(limit(&params, 50).unwrap_err().status, 400);
+        params.insert("limit".to_string(), "200".to_string());
+        assert_eq!(limit(&params, 50).unwrap(), 200);
+        assert_eq!(limit(&HttpParams::new(), 50).unwrap(), 50);
+        assert_eq!(ApiError::from(std::fmt::Error).status, 500);
+        let response = error.into_response();
+        assert_eq!(response.code, 400);
+        assert_eq!(
+            response.payload.as_deref(),
+            Some(&br#"{"error":{"code":400,"message":"invalid parameter 'limit'"}}"#[..])
+        );
+
+        let call = |status| {
+            let handler = handler(move |_, _, _, _| Err(ApiError::new(status, "/home/me")));
+            let response = handler(String::new(), HttpHeaders::new(), params.clone(), None);
+            String::from_utf8(response.unwrap().payload.unwrap()).unwrap()
+        };
+        assert!(call(500).contains("internal server error"));
+        assert!(call(501).contains("/home/me"));
+    }
+}
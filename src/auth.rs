@@ -0,0 +1,292 @@
+//! Access control for `serve`: a static token and/or users with argon2
+//! password hashes, checked for every request before it is routed.
+use crate::api::ApiError;
+use crate::minhttpd::{HttpGuard, HttpHeaders, HttpParams, HttpResponse, HttpUri};
+use argon2::password_hash::rand_core::OsRng;
+use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use argon2::Argon2;
+use base64::Engine;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, OnceLock};
+
+const TOKEN_COOKIE: &str = "imgfind_token";
+
+#[derive(Default)]
+pub struct Auth {
+    pub token: Option<String>,
+    /// User names and their argon2 password hashes in PHC string format.
+    pub users: Vec<(String, String)>,
+    /// `Authorization` headers that already passed verification with the
+    /// user they belong to, so argon2 does not run again for every thumbnail
+    /// on a page.
+    verified: Mutex<HashMap<String, String>>,
+    /// Served over TLS, so the cookie must never be sent without it.
+    pub secure: bool,
+}
+
+/// Hashes a password for use with `serve --user name:hash`.
+pub fn hash_password(password: &str) -> Result<String, argon2::password_hash::Error> {
+    let salt = SaltString::generate(&mut OsRng);
+    Ok(Argon2::default()
+        .hash_password(password.as_bytes(), &salt)?
+        .to_string())
+}
+
+/// Parses a `name:hash` pair as given to `serve --user`.
+pub fn parse_user(user: &str) -> Result<(String, String), String> {
+    let (name, hash) = user
+        .split_once(':')
+        .ok_or_else(|| format!("expected <name>:<hash>, got '{}'", user))?;
+    PasswordHash::new(hash).map_err(|e| format!("invalid password hash for '{}': {}", name, e))?;
+    Ok((name.to_string(), hash.to_string()))
+}
+
+/// A hash of a random password, for checking credentials of unknown users.
+fn dummy_hash() -> &'static str {
+    static DUMMY: OnceLock<String> = OnceLock::new();
+    DUMMY.get_or_init(|| {
+        let password = SaltString::generate(&mut OsRng);
+        hash_password(password.as_str()).unwrap_or_default()
+    })
+}
+
+fn constant_time_eq(a: &str, b: &str) -> bool {
+    a.len() == b.len()
+        && a.bytes()
+            .zip(b.bytes())
+            .fold(0, |acc, (x, y)| acc | (x ^ y))
+            == 0
+}
+
+/// Percent-encodes a query component, the inverse of what the server does
+/// to incoming parameters.
+fn url_encode(s: &str) -> String {
+    s.bytes()
+        .map(|byte| match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                (byte as char).to_string()
+            }
+            _ => format!("%{:02X}", byte),
+        })
+        .collect()
+}
+
+impl Auth {
+    pub fn is_enabled(&self) -> bool {
+        self.token.is_some() || !self.users.is_empty()
+    }
+
+    fn check_token(&self, token: &str) -> bool {
+        self.token
+            .as_deref()
+            .is_some_and(|expected| constant_time_eq(expected, token))
+    }
+
+    fn check_user(&self, name: &str, password: &str) -> bool {
+        let known = self.users.iter().find(|(user, _)| user == name);
+        // unknown names are verified against a throwaway hash, so the time
+        // taken does not tell which names exist
+        let hash = match known {
+            Some((_, hash)) => hash.as_str(),
+            None => dummy_hash(),
+        };
+        let verified = PasswordHash::new(hash).is_ok_and(|hash| {
+            Argon2::default()
+                .verify_password(password.as_bytes(), &hash)
+                .is_ok()
+        });
+        known.is_some() && verified
+    }
+
+    /// Verifies an `Authorization` header, returning the user it names, or
+    /// an empty name for the token.
+    fn check_authorization(&self, authorization: &str) -> Option<String> {
+        if let Some(name) = self.verified.lock().unwrap().get(authorization) {
+            return Some(name.clone());
+        }
+        let user = match authorization.split_once(' ') {
+            Some((scheme, token)) if scheme.eq_ignore_ascii_case("bearer") => {
+                self.check_token(token.trim()).then(String::new)
+            }
+            Some((scheme, credentials)) if scheme.eq_ignore_ascii_case("basic") => {
+                let decoded = base64::engine::general_purpose::STANDARD
+                    .decode(credentials.trim())
+                    .ok()
+                    .and_then(|bytes| String::from_utf8(bytes).ok());
+                match decoded.as_deref().and_then(|d| d.split_once(':')) {
+                    Some((name, password)) if self.check_user(name, password) => {
+                        Some(name.to_string())
+                    }
+                    // the token also works as a password with any user name,
+                    // which is then not taken to be anyone's
+                    Some((_, password)) => self.check_token(password).then(String::new),
+                    None => None,
+                }
+            }
+            _ => None,
+        };
+        if let Some(name) = &user {
+            self.verified
+                .lock()
+                .unwrap()
+                .insert(authorization.to_string(), name.clone());
+        }
+        user
+    }
+
+    /// The user a request was made as, from credentials verified against
+    /// `--user`. Empty for token access and when `serve` runs without
+    /// authentication, so those share one set of user data.
+    pub fn user_name(&self, headers: &HttpHeaders) -> String {
+        headers
+            .get("authorization")
+            .and_then(|authorization| self.check_authorization(authorization))
+            .unwrap_or_default()
+    }
+
+    fn cookie_token(headers: &HttpHeaders) -> Option<&str> {
+        headers.get("cookie")?.split(';').find_map(|cookie| {
+            let (name, value) = cookie.trim().split_once('=')?;
+            (name == TOKEN_COOKIE).then_some(value)
+        })
+    }
+
+    /// Returns `None` if the request may proceed, or the response to send instead.
+    fn authorize(
+        &self,
+        uri: &HttpUri,
+        headers: &HttpHeaders,
+        params: &HttpParams,
+    ) -> Option<HttpResponse> {
+        if let Some(authorization) = headers.get("authorization") {
+            if self.check_authorization(authorization).is_some() {
+                return None;
+            }
+        }
+        if Self::cookie_token(headers).is_some_and(|token| self.check_token(token)) {
+            return None;
+        }
+        if let Some(token) = params.get("token").filter(|token| self.check_token(token)) {
+            // move the token from the link into a cookie, so that images and
+            // API calls made by the page are authorized too
+            let query: Vec<String> = params
+                .iter()
+                .filter(|(name, _)| *name != "token")
+                .map(|(name, value)| format!("{}={}", url_encode(name), url_encode(value)))
+                .collect();
+            // `//host` would be taken as another site by the browser
+            let path = format!("/{}", uri.trim_start_matches(['/', '\\']));
+            let location = if query.is_empty() {
+                path
+            } else {
+                format!("{}?{}", path, query.join("&"))
+            };
+            return Some(
+                HttpResponse::builder()
+                    .set_code(302)
+                    .add_header("Location", location)
+                    .add_header(
+                        "Set-Cookie",
+                        format!(
+                            "{}={}; Path=/; HttpOnly; SameSite=Strict{}",
+                            TOKEN_COOKIE,
+                            token,
+                            if self.secure { "; Secure" } else { "" }
+                        ),
+                    )
+                    .build(),
+            );
+        }
+        let mut response = ApiError::new(401, "authentication required").into_response();
+        response.add_header("WWW-Authenticate", "Basic realm=\"imgfind\"");
+        Some(response)
+    }
+
+    pub fn into_guard(self: Arc<Self>) -> HttpGuard {
+        Box::new(move |uri, headers, params| self.authorize(uri, headers, params))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn basic(name: &str, password: &str) -> String {
+        let credentials = format!("{}:{}", name, password);
+        format!(
+            "Basic {}",
+            base64::engine::general_purpose::STANDARD.encode(credentials)
+        )
+    }
+
+    #[test]
+    fn test_authorization() {
+        let hash = hash_password("hunter2").unwrap();
+        let auth = Auth {
+            token: Some("s3cret".to_string()),
+            users: vec![parse_user(&format!("alice:{}", hash)).unwrap()],
+            ..Default::default()
+        };
+        assert_eq!(
+            auth.check_authorization("Bearer s3cret"),
+            Some(String::new())
+        );
+        assert_eq!(auth.check_authorization("Bearer S3CRET"), None);
+        assert_eq!(
+            auth.check_authorization(&basic("alice", "hunter2")),
+            Some("alice".to_string())
+        );
+        assert_eq!(
+            auth.check_authorization(&basic("anyone", "s3cret")),
+            Some(String::new())
+        );
+        assert_eq!(auth.check_authorization(&basic("alice", "hunter3")), None);
+        assert_eq!(auth.check_authorization(&basic("bob", "hunter2")), None);
+
+        let mut headers = HttpHeaders::new();
+        assert_eq!(auth.user_name(&headers), "");
+        headers.insert("authorization".to_string(), basic("alice", "hunter2"));
+        assert_eq!(auth.user_name(&headers), "alice");
+        // the token with someone's name does not give access to their data
+        headers.insert("authorization".to_string(), basic("alice", "s3cret"));
+        assert_eq!(auth.user_name(&headers), "");
+        // nor does a name when nothing is checked
+        headers.insert("authorization".to_string(), basic("alice", "hunter3"));
+        assert_eq!(Auth::default().user_name(&headers), "");
+    }
+
+    #[test]
+    fn test_token_redirect() {
+        let auth = Auth {
+            token: Some("s3cret".to_string()),
+            secure: true,
+            ..Default::default()
+        };
+        let params = HttpParams::from([
+            ("token".to_string(), "s3cret".to_string()),
+            ("q".to_string(), "a b".to_string()),
+        ]);
+        let response = auth
+            .authorize(
+                &"//evil.example/x".to_string(),
+                &HttpHeaders::new(),
+                &params,
+            )
+            .unwrap();
+        let header = |name: &str| {
+            response
+                .headers
+                .iter()
+                .find(|(n, _)| n == name)
+                .map(|(_, value)| value.as_str())
+        };
+        assert_eq!(header("Location"), Some("/evil.example/x?q=a%20b"));
+        assert!(header("Set-Cookie").unwrap().ends_with("; Secure"));
+    }
+
+    #[test]
+    fn test_url_encode() {
+        assert_eq!(url_encode("a b&c/d.jpg"), "a%20b%26c%2Fd.jpg");
+        assert_eq!(url_encode("猫"), "%E7%8C%AB");
+    }
+}
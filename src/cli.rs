@@ -0,0 +1,276 @@
+//! Command line arguments. Invalid arguments exit with status 2 and a usage
+//! message; failures while running a command exit with status 1.
+use crate::export::ExportFormat;
+use crate::logging::LogFormat;
+use crate::output::Format;
+use crate::{auth, dupes, CLUSTER_COUNT, DUPES_THRESHOLD, TAG_THRESHOLD};
+use clap::{Args, Parser, Subcommand};
+use std::net::IpAddr;
+use std::num::NonZeroUsize;
+use std::path::PathBuf;
+
+#[derive(Debug, Parser)]
+#[command(
+    name = "imgfind",
+    version,
+    about = "Search local images by text or by example with CLIP"
+)]
+pub struct Cli {
+    /// Config file [default: imgfind/config.toml in the user's config directory]
+    #[arg(long, global = true, value_name = "FILE", env = "IMGFIND_CONFIG")]
+    pub config: Option<PathBuf>,
+    /// Index file of the default collection
+    #[arg(long, global = true, value_name = "FILE", env = "IMGFIND_DATABASE")]
+    pub database: Option<PathBuf>,
+    /// Collection to work on [default: the default collection]
+    #[arg(
+        short,
+        long,
+        global = true,
+        value_name = "NAME",
+        env = "IMGFIND_COLLECTION"
+    )]
+    pub collection: Option<String>,
+    /// Directory holding model.safetensors and tokenizer.json
+    #[arg(long, global = true, value_name = "DIR", env = "IMGFIND_MODEL_DIR")]
+    pub model_dir: Option<PathBuf>,
+    /// Tokenizer file [default: tokenizer.json in the model directory]
+    #[arg(long, global = true, value_name = "FILE", env = "IMGFIND_TOKENIZER")]
+    pub tokenizer: Option<PathBuf>,
+    /// Threads used to run the model and to score images [default: one per core]
+    #[arg(long, global = true, value_name = "N")]
+    pub threads: Option<NonZeroUsize>,
+    /// Least severe messages logged: off, error, warn, info, debug or trace
+    #[arg(
+        long,
+        global = true,
+        value_name = "LEVEL",
+        default_value = "info",
+        env = "IMGFIND_LOG"
+    )]
+    pub log_level: log::LevelFilter,
+    /// Write log lines as text or as JSON objects
+    #[arg(
+        long,
+        global = true,
+        value_enum,
+        default_value_t,
+        env = "IMGFIND_LOG_FORMAT"
+    )]
+    pub log_format: LogFormat,
+    #[command(subcommand)]
+    pub command: Command,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Index the images in a directory
+    Add {
+        /// Directory to index [default: the configured roots]
+        paths: Vec<String>,
+        /// Also create thumbnails for the web UI
+        #[arg(long)]
+        thumbnails: bool,
+        /// Index only the images listed in a failure report
+        #[arg(long, value_name = "REPORT", conflicts_with = "paths")]
+        retry: Option<PathBuf>,
+        /// Where to write the images that failed [default: <database>.failures.jsonl]
+        #[arg(long, value_name = "FILE")]
+        failures: Option<PathBuf>,
+        /// Follow symbolic links to files and directories
+        #[arg(short = 'L', long)]
+        follow_symlinks: bool,
+        /// Do not enter directories on other file systems
+        #[arg(short = 'x', long)]
+        one_file_system: bool,
+    },
+    /// Search the index by text
+    Find {
+        text: String,
+        /// Search every collection and merge the results
+        #[arg(long, conflicts_with = "collection")]
+        all: bool,
+        #[command(flatten)]
+        filter: FilterArgs,
+        #[arg(long, value_enum, default_value_t)]
+        format: Format,
+    },
+    /// List the indexed images
+    List {
+        /// List every collection
+        #[arg(long, conflicts_with = "collection")]
+        all: bool,
+        #[arg(long, value_enum, default_value_t)]
+        format: Format,
+    },
+    /// Show how many images each collection holds
+    Stats {
+        #[arg(long, value_enum, default_value_t)]
+        format: Format,
+    },
+    /// Tag images with the labels they most likely show
+    Tag {
+        /// Comma separated labels, at least two
+        tags: String,
+        /// Probability above which a tag is assigned
+        #[arg(
+            long,
+            value_name = "PROBABILITY",
+            default_value_t = TAG_THRESHOLD,
+            value_parser = dupes::parse_threshold
+        )]
+        threshold: f32,
+    },
+    /// List groups of near-duplicate images
+    Dupes {
+        /// Cosine similarity above which images count as duplicates
+        #[arg(
+            long,
+            value_name = "SCORE",
+            default_value_t = DUPES_THRESHOLD,
+            value_parser = dupes::parse_threshold
+        )]
+        threshold: f32,
+        /// Confirm pairs with a perceptual hash
+        #[arg(long)]
+        phash: bool,
+    },
+    /// Group images into labelled topics
+    Cluster {
+        /// Number of topics
+        #[arg(short = 'k', long = "clusters", value_name = "COUNT", default_value_t = CLUSTER_COUNT)]
+        k: usize,
+        /// File with one candidate label per line
+        #[arg(long, value_name = "FILE")]
+        vocab: Option<PathBuf>,
+    },
+    /// Keep the index current as images change in directories
+    Watch {
+        /// Directories to watch [default: the configured roots]
+        paths: Vec<String>,
+        /// Also create thumbnails for the web UI
+        #[arg(long)]
+        thumbnails: bool,
+    },
+    /// Remove images that no longer exist from the index, listing them
+    Check {
+        #[arg(long, value_enum, default_value_t)]
+        format: Format,
+    },
+    /// List the configured collections
+    Collections,
+    /// List the library roots, or point one at the place it was moved to
+    Remap {
+        /// Name or old location of the root
+        #[arg(requires = "location")]
+        root: Option<String>,
+        /// Where the root is now
+        location: Option<PathBuf>,
+    },
+    /// Write the paths and embeddings of the collection to a file
+    Export {
+        output: PathBuf,
+        /// [default: from the extension of the file]
+        #[arg(long, value_enum)]
+        format: Option<ExportFormat>,
+    },
+    /// Add embeddings computed elsewhere to the collection
+    Import {
+        input: PathBuf,
+        /// [default: from the extension of the file]
+        #[arg(long, value_enum)]
+        format: Option<ExportFormat>,
+        /// Model the embeddings are from, if the file does not say
+        #[arg(long)]
+        model: Option<String>,
+        /// Replace the embeddings of images already indexed
+        #[arg(long)]
+        overwrite: bool,
+    },
+    /// Read a password from stdin and print a hash for `serve --user`
+    HashPassword,
+    /// Serve the web UI and HTTP API
+    Serve(ServeArgs),
+    /// Print a shell completion script
+    Completions { shell: clap_complete::Shell },
+}
+
+#[derive(Debug, Args)]
+pub struct FilterArgs {
+    /// Drop results scoring below this cosine similarity
+    #[arg(long, value_name = "SCORE")]
+    pub min_score: Option<f32>,
+    /// Cut the results where similarity drops sharply
+    #[arg(long)]
+    pub auto_cutoff: bool,
+    /// Comma separated tags every result must carry
+    #[arg(long, value_name = "TAGS")]
+    pub tag: Vec<String>,
+}
+
+#[derive(Debug, Args)]
+pub struct ServeArgs {
+    /// Port to listen on [default: from the config]
+    pub port: Option<u16>,
+    /// Address to listen on [default: 127.0.0.1]
+    #[arg(long, value_name = "ADDRESS")]
+    pub bind: Option<IpAddr>,
+    /// Token required to access the server
+    #[arg(long)]
+    pub token: Option<String>,
+    /// User allowed to log in, as a name and a hash from `hash-password`
+    #[arg(long, value_name = "NAME:HASH", value_parser = auth::parse_user)]
+    pub user: Vec<(String, String)>,
+    /// Certificate chain to serve HTTPS with (needs the tls feature)
+    #[arg(long, value_name = "FILE", requires = "tls_key")]
+    pub tls_cert: Option<String>,
+    /// Private key of the certificate
+    #[arg(long, value_name = "FILE", requires = "tls_cert")]
+    pub tls_key: Option<String>,
+    /// Keep the index current as images change in this directory
+    #[arg(long, value_name = "PATH")]
+    pub watch: Vec<String>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::CommandFactory;
+
+    #[test]
+    fn test_cli() {
+        Cli::command().debug_assert();
+
+        let cli = Cli::try_parse_from(["imgfind", "find", "cat", "--tag", "a,b", "--threads", "2"])
+            .unwrap();
+        assert_eq!(cli.threads, NonZeroUsize::new(2));
+        assert!(
+            matches!(cli.command, Command::Find { ref text, all: false, ref filter, format }
+            if text == "cat" && filter.tag == ["a,b"] && format == Format::Text)
+        );
+        let cli = Cli::try_parse_from(["imgfind", "check", "--format", "paths0"]).unwrap();
+        assert!(matches!(
+            cli.command,
+            Command::Check {
+                format: Format::Paths0
+            }
+        ));
+        let cli = Cli::try_parse_from(["imgfind", "-c", "work", "add"]).unwrap();
+        assert_eq!(cli.collection.as_deref(), Some("work"));
+        assert_eq!(cli.log_level, log::LevelFilter::Info);
+        assert!(Cli::try_parse_from(["imgfind", "add", "dir", "--retry", "r.jsonl"]).is_err());
+        assert!(Cli::try_parse_from(["imgfind", "find", "cat", "--all", "-c", "work"]).is_err());
+
+        let error = Cli::try_parse_from(["imgfind", "serve", "80", "--tls-cert", "c.pem"]);
+        assert_eq!(error.unwrap_err().exit_code(), 2);
+        assert!(Cli::try_parse_from(["imgfind", "dupes", "--threshold", "high"]).is_err());
+        assert!(Cli::try_parse_from(["imgfind", "dupes", "--threshold", "0"]).is_err());
+        assert!(Cli::try_parse_from(["imgfind", "dupes", "--threshold", "NaN"]).is_err());
+        assert!(Cli::try_parse_from(["imgfind", "dupes", "--threshold", "1"]).is_ok());
+        assert!(Cli::try_parse_from(["imgfind", "tag", "a,b", "--threshold", "1.5"]).is_err());
+        let cli = Cli::try_parse_from(["imgfind", "cluster", "-k", "8"]).unwrap();
+        assert!(matches!(cli.command, Command::Cluster { k: 8, .. }));
+        assert!(Cli::try_parse_from(["imgfind", "cluster", "--clusters", "8"]).is_ok());
+        assert!(Cli::try_parse_from(["imgfind", "search"]).is_err());
+    }
+}
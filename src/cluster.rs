@@ -0,0 +1,231 @@
+//! Groups the library into visual topics with spherical k-means, and names
+//! each topic after the text prompt closest to its centroid.
+use crate::database::{Database, Embedding, Topic};
+use crate::error::Result;
+use crate::{dot_product, encode_text, model, normalize};
+use tokenizers::tokenizer::Tokenizer;
+
+/// Prompts used to name topics when no vocabulary file is given.
+pub const DEFAULT_VOCABULARY: &[&str] = &[
+    "people",
+    "a selfie",
+    "a group photo",
+    "a baby",
+    "a wedding",
+    "a cat",
+    "a dog",
+    "a bird",
+    "flowers",
+    "trees and forest",
+    "mountains",
+    "the beach",
+    "the sea",
+    "a lake",
+    "snow",
+    "the sky",
+    "a sunset",
+    "a city street",
+    "buildings",
+    "an interior room",
+    "a car",
+    "a train",
+    "an airplane",
+    "food",
+    "drinks",
+    "a restaurant",
+    "a concert",
+    "sports",
+    "a document",
+    "a receipt",
+    "a screenshot",
+    "a chart",
+    "text",
+    "a whiteboard",
+    "a drawing",
+    "anime",
+    "a meme",
+    "a map",
+    "a computer",
+    "a phone",
+    "clothes",
+    "shoes",
+    "furniture",
+    "a garden",
+    "a night scene",
+    "fireworks",
+    "a museum",
+    "a painting",
+];
+
+/// Small deterministic generator for k-means++ seeding, so runs are repeatable.
+struct XorShift(u64);
+
+impl XorShift {
+    fn next_f32(&mut self) -> f32 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        (self.0 >> 40) as f32 / (1u64 << 24) as f32
+    }
+}
+
+fn nearest(embedding: &[f32], centroids: &[Embedding]) -> (usize, f32) {
+    centroids
+        .iter()
+        .map(|c| dot_product(embedding, c))
+        .enumerate()
+        .fold(
+            (0, f32::MIN),
+            |best, (i, s)| if s > best.1 { (i, s) } else { best },
+        )
+}
+
+/// Assigns every embedding to its nearest centroid, on [`crate::thread_count`]
+/// threads.
+fn assign(embeddings: &[&Embedding], centroids: &[Embedding]) -> Vec<(usize, f32)> {
+    let block = embeddings.len().div_ceil(crate::thread_count()).max(1);
+    std::thread::scope(|s| {
+        let handles: Vec<_> = embeddings
+            .chunks(block)
+            .map(|chunk| {
+                s.spawn(move || {
+                    chunk
+                        .iter()
+                        .map(|e| nearest(e, centroids))
+                        .collect::<Vec<_>>()
+                })
+            })
+            .collect();
+        handles
+            .into_iter()
+            .flat_map(|h| h.join().expect("cluster thread panicked"))
+            .collect()
+    })
+}
+
+/// k-means++ seeding: each new centroid is drawn with probability
+/// proportional to the squared distance from the nearest existing one.
+fn seed(embeddings: &[&Embedding], k: usize) -> Vec<Embedding> {
+    let mut rng = XorShift(0x9E37_79B9_7F4A_7C15);
+    let mut centroids = vec![embeddings[0].clone()];
+    while centroids.len() < k {
+        let weights: Vec<f32> = assign(embeddings, &centroids)
+            .iter()
+            .map(|(_, s)| (1.0 - s).max(0.0).powi(2))
+            .collect();
+        let total: f32 = weights.iter().sum();
+        if total <= 0.0 {
+            break;
+        }
+        let mut target = rng.next_f32() * total;
+        let mut chosen = embeddings.len() - 1;
+        for (i, w) in weights.iter().enumerate() {
+            if target < *w {
+                chosen = i;
+                break;
+            }
+            target -= w;
+        }
+        centroids.push(embeddings[chosen].clone());
+    }
+    centroids
+}
+
+/// Runs spherical k-means and returns the unit-length centroids together with
+/// the centroid index of every embedding.
+pub fn kmeans(
+    embeddings: &[&Embedding],
+    k: usize,
+    max_iter: usize,
+) -> (Vec<Embedding>, Vec<usize>) {
+    if embeddings.is_empty() || k == 0 {
+        return (Vec::new(), Vec::new());
+    }
+    let mut centroids = seed(embeddings, k.min(embeddings.len()));
+    let mut labels = vec![usize::MAX; embeddings.len()];
+    for _ in 0..max_iter {
+        let assignment = assign(embeddings, &centroids);
+        let changed = assignment
+            .iter()
+            .zip(labels.iter())
+            .filter(|((new, _), old)| new != *old)
+            .count();
+        labels = assignment.iter().map(|(i, _)| *i).collect();
+        if changed == 0 {
+            break;
+        }
+        let dim = centroids[0].len();
+        let mut sums = vec![vec![0f32; dim]; centroids.len()];
+        for (embedding, &label) in embeddings.iter().zip(labels.iter()) {
+            for (s, x) in sums[label].iter_mut().zip(embedding.iter()) {
+                *s += x;
+            }
+        }
+        for (centroid, sum) in centroids.iter_mut().zip(sums) {
+            // an empty cluster keeps its previous centroid
+            if sum.iter().any(|x| *x != 0.0) {
+                *centroid = normalize(&sum);
+            }
+        }
+    }
+    (centroids, labels)
+}
+
+/// Names each centroid after the best matching prompt in `vocabulary`.
+pub fn label_topics(
+    centroids: &[Embedding],
+    model: &model::ClipTextTransformer,
+    tokenizer: &Tokenizer,
+    vocabulary: &[String],
+) -> Result<Vec<String>> {
+    let prompts = vocabulary
+        .iter()
+        .map(|word| encode_text(model, tokenizer, &format!("a photo of {}", word)))
+        .collect::<Result<Vec<_>>>()?;
+    Ok(centroids
+        .iter()
+        .map(|centroid| vocabulary[nearest(centroid, &prompts).0].clone())
+        .collect())
+}
+
+/// Clusters the whole database into `k` topics and stores the result in it.
+pub fn cluster_database(
+    database: &mut Database,
+    model: &model::ClipTextTransformer,
+    tokenizer: &Tokenizer,
+    k: usize,
+    vocabulary: &[String],
+) -> Result<()> {
+    const MAX_ITER: usize = 50;
+    let (paths, embeddings): (Vec<_>, Vec<_>) = database.embeddings.iter().unzip();
+    let (centroids, labels) = kmeans(&embeddings, k, MAX_ITER);
+    let names = label_topics(&centroids, model, tokenizer, vocabulary)?;
+    let topic_of = paths.into_iter().cloned().zip(labels).collect();
+    database.topics = names
+        .into_iter()
+        .zip(centroids)
+        .map(|(label, centroid)| Topic { label, centroid })
+        .collect();
+    database.topic_of = topic_of;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_kmeans() {
+        let points: Vec<Embedding> = [[1.0, 0.1], [0.9, 0.2], [0.1, 1.0], [0.2, 0.9], [1.0, 0.0]]
+            .iter()
+            .map(|p| normalize(p))
+            .collect();
+        let refs: Vec<&Embedding> = points.iter().collect();
+        let (centroids, labels) = kmeans(&refs, 2, 10);
+        assert_eq!(centroids.len(), 2);
+        assert_eq!(labels[0], labels[1]);
+        assert_eq!(labels[0], labels[4]);
+        assert_eq!(labels[2], labels[3]);
+        assert_ne!(labels[0], labels[2]);
+    }
+}
@@ -0,0 +1,331 @@
+use crate::scan;
+use globset::{Glob, GlobSetBuilder};
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+
+const CONFIG_FILE: &str = "config.toml";
+const DATABASE_FILE: &str = "database.bin";
+const MODEL_DIR: &str = "clip";
+pub const DEFAULT_COLLECTION: &str = "default";
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub database: Option<PathBuf>,
+    pub model_dir: Option<PathBuf>,
+    pub tokenizer: Option<PathBuf>,
+    pub roots: Vec<String>,
+    pub exclude: Vec<String>,
+    pub min_size: u64,
+    pub min_resolution: u32,
+    pub hidden: bool,
+    pub system_folders: bool,
+    pub follow_symlinks: bool,
+    pub one_file_system: bool,
+    pub collections: BTreeMap<String, CollectionConfig>,
+    pub serve: ServeConfig,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CollectionConfig {
+    /// Defaults to `<name>.bin` next to the default collection's database.
+    pub database: Option<PathBuf>,
+    pub roots: Vec<String>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Collection {
+    pub name: String,
+    pub database: PathBuf,
+    pub roots: Vec<String>,
+}
+
+impl Collection {
+    /// The given paths, or the roots of the collection if none are given.
+    pub fn paths_or_roots(&self, paths: Vec<String>) -> Result<Vec<String>, String> {
+        match (paths.is_empty(), self.roots.is_empty()) {
+            (false, _) => Ok(paths),
+            (true, false) => Ok(self.roots.clone()),
+            (true, true) => Err(format!(
+                "no path given and no roots configured for collection '{}'",
+                self.name
+            )),
+        }
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServeConfig {
+    pub port: Option<u16>,
+    pub bind: Option<IpAddr>,
+    pub token: Option<String>,
+    pub users: Vec<String>,
+    pub tls_cert: Option<String>,
+    pub tls_key: Option<String>,
+    pub watch: bool,
+}
+
+fn home_dir() -> Option<PathBuf> {
+    std::env::var_os(if cfg!(windows) { "USERPROFILE" } else { "HOME" }).map(PathBuf::from)
+}
+
+/// The variable if it is set to an absolute path, else `fallback` below the
+/// home directory.
+fn xdg_dir(var: &str, fallback: &str) -> Option<PathBuf> {
+    std::env::var_os(var)
+        .map(PathBuf::from)
+        .filter(|dir| dir.is_absolute())
+        .or_else(|| Some(home_dir()?.join(fallback)))
+}
+
+fn config_dir() -> Option<PathBuf> {
+    if cfg!(windows) {
+        std::env::var_os("APPDATA").map(PathBuf::from)
+    } else {
+        xdg_dir("XDG_CONFIG_HOME", ".config")
+    }
+    .map(|dir| dir.join("imgfind"))
+}
+
+fn data_dir() -> Option<PathBuf> {
+    if cfg!(windows) {
+        std::env::var_os("LOCALAPPDATA").map(PathBuf::from)
+    } else {
+        xdg_dir("XDG_DATA_HOME", ".local/share")
+    }
+    .map(|dir| dir.join("imgfind"))
+}
+
+fn resolve(base: &Path, path: &Path) -> PathBuf {
+    let path = match (path.strip_prefix("~"), home_dir()) {
+        (Ok(rest), Some(home)) => home.join(rest),
+        _ => path.to_path_buf(),
+    };
+    base.join(path)
+}
+
+impl Config {
+    pub fn load(path: Option<&Path>) -> Result<Self, String> {
+        let path = match path {
+            Some(path) => path.to_path_buf(),
+            None => match config_dir().map(|dir| dir.join(CONFIG_FILE)) {
+                Some(path) if path.is_file() => path,
+                _ => return Ok(Self::default()),
+            },
+        };
+        let text = std::fs::read_to_string(&path)
+            .map_err(|e| format!("failed to read {}: {}", path.display(), e))?;
+        let config: Self =
+            toml::from_str(&text).map_err(|e| format!("invalid {}: {}", path.display(), e))?;
+        config
+            .check()
+            .map_err(|e| format!("invalid {}: {}", path.display(), e))?;
+        Ok(config.relative_to(path.parent().unwrap_or(Path::new(""))))
+    }
+
+    fn check(&self) -> Result<(), String> {
+        // the top level `database` and `roots` already set it up
+        if self.collections.contains_key(DEFAULT_COLLECTION) {
+            return Err(format!(
+                "[collections.{}] is reserved, use the top level `database` and `roots` instead",
+                DEFAULT_COLLECTION
+            ));
+        }
+        Ok(())
+    }
+
+    fn relative_to(mut self, base: &Path) -> Self {
+        let path = |path: &Path| resolve(base, path);
+        let string = |path: &str| path_string(resolve(base, Path::new(path)));
+        self.database = self.database.as_deref().map(path);
+        self.model_dir = self.model_dir.as_deref().map(path);
+        self.tokenizer = self.tokenizer.as_deref().map(path);
+        self.roots = self.roots.iter().map(|root| string(root)).collect();
+        for collection in self.collections.values_mut() {
+            collection.database = collection.database.as_deref().map(path);
+            collection.roots = collection.roots.iter().map(|root| string(root)).collect();
+        }
+        self.serve.tls_cert = self.serve.tls_cert.as_deref().map(string);
+        self.serve.tls_key = self.serve.tls_key.as_deref().map(string);
+        self
+    }
+
+    /// A `database.bin` in the working directory is still used, as older
+    /// versions kept it there.
+    fn database(&self, given: Option<PathBuf>) -> PathBuf {
+        given.or_else(|| self.database.clone()).unwrap_or_else(|| {
+            let local = PathBuf::from(DATABASE_FILE);
+            match data_dir() {
+                Some(dir) if !local.exists() => dir.join(DATABASE_FILE),
+                _ => local,
+            }
+        })
+    }
+
+    /// Release packages put `clip` next to the executable.
+    pub fn model_dir(&self, given: Option<PathBuf>) -> PathBuf {
+        given
+            .or_else(|| self.model_dir.clone())
+            .or_else(|| {
+                let local = PathBuf::from(MODEL_DIR);
+                if local.is_dir() {
+                    return Some(local);
+                }
+                let exe = std::env::current_exe().ok()?;
+                Some(exe.parent()?.join(MODEL_DIR)).filter(|dir| dir.is_dir())
+            })
+            .unwrap_or_else(|| PathBuf::from(MODEL_DIR))
+    }
+
+    pub fn tokenizer(&self, given: Option<PathBuf>, model_dir: &Path) -> PathBuf {
+        given
+            .or_else(|| self.tokenizer.clone())
+            .unwrap_or_else(|| model_dir.join(crate::TOKENIZER_FILE))
+    }
+
+    /// The default collection first.
+    pub fn collections(&self, database: Option<PathBuf>) -> Result<Vec<Collection>, String> {
+        let default_database = self.database(database);
+        let named =
+            self.collections
+                .iter()
+                .map(|(name, collection)| Collection {
+                    name: name.clone(),
+                    database: collection.database.clone().unwrap_or_else(|| {
+                        default_database.with_file_name(format!("{}.bin", name))
+                    }),
+                    roots: collection.roots.clone(),
+                });
+        let collections: Vec<Collection> = std::iter::once(Collection {
+            name: DEFAULT_COLLECTION.to_string(),
+            database: default_database.clone(),
+            roots: self.roots.clone(),
+        })
+        .chain(named)
+        .collect();
+        for (i, collection) in collections.iter().enumerate() {
+            if let Some(other) = collections[..i]
+                .iter()
+                .find(|other| other.database == collection.database)
+            {
+                return Err(format!(
+                    "collections '{}' and '{}' share the database {}",
+                    other.name,
+                    collection.name,
+                    collection.database.display()
+                ));
+            }
+        }
+        Ok(collections)
+    }
+
+    pub fn collection(
+        &self,
+        name: Option<&str>,
+        database: Option<PathBuf>,
+    ) -> Result<Collection, String> {
+        let name = name.unwrap_or(DEFAULT_COLLECTION);
+        self.collections(database)?
+            .into_iter()
+            .find(|collection| collection.name == name)
+            .ok_or_else(|| format!("unknown collection '{}'", name))
+    }
+
+    /// Exclude patterns are matched against whole paths.
+    pub fn scan_filter(&self) -> Result<scan::Filter, String> {
+        let mut set = GlobSetBuilder::new();
+        for pattern in self.exclude.iter() {
+            let glob = Glob::new(pattern)
+                .map_err(|e| format!("invalid exclude pattern '{}': {}", pattern, e))?;
+            set.add(glob);
+        }
+        Ok(scan::Filter {
+            exclude: set.build().map_err(|e| e.to_string())?,
+            min_size: self.min_size,
+            min_resolution: self.min_resolution,
+            hidden: self.hidden,
+            system_folders: self.system_folders,
+            follow_symlinks: self.follow_symlinks,
+            one_file_system: self.one_file_system,
+        })
+    }
+}
+
+fn path_string(path: PathBuf) -> String {
+    path.to_string_lossy().to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_config() {
+        let config: Config = toml::from_str(
+            r#"
+            database = "index/database.bin"
+            model_dir = "/opt/clip"
+            roots = ["photos"]
+            exclude = ["**/.thumbnails/**", "*.tmp"]
+            min_resolution = 64
+
+            [collections.work]
+            roots = ["/srv/work"]
+
+            [serve]
+            port = 8080
+            watch = true
+            "#,
+        )
+        .unwrap();
+        let config = config.relative_to(Path::new("/etc/imgfind"));
+        let default = config.collection(None, None).unwrap();
+        assert_eq!(
+            default.database,
+            Path::new("/etc/imgfind/index/database.bin")
+        );
+        assert_eq!(config.database(Some("db.bin".into())), Path::new("db.bin"));
+        let work = config.collection(Some("work"), None).unwrap();
+        assert_eq!(work.database, Path::new("/etc/imgfind/index/work.bin"));
+        assert_eq!(
+            config.collections(None).unwrap(),
+            [default.clone(), work.clone()]
+        );
+        assert!(config.collection(Some("home"), None).is_err());
+        assert_eq!(config.model_dir(None), Path::new("/opt/clip"));
+        assert_eq!(
+            config.tokenizer(None, Path::new("/opt/clip")),
+            Path::new("/opt/clip/tokenizer.json")
+        );
+        assert_eq!(
+            default.paths_or_roots(Vec::new()).unwrap(),
+            [path_string(Path::new("/etc/imgfind").join("photos"))]
+        );
+        assert_eq!(config.serve.port, Some(8080));
+
+        let filter = config.scan_filter().unwrap();
+        assert_eq!(filter.min_resolution, 64);
+        assert!(!filter.hidden);
+        let exclude = filter.exclude;
+        assert!(exclude.is_match("/home/me/photos/.thumbnails/a.jpg"));
+        assert!(exclude.is_match("photos/a.tmp"));
+        assert!(!exclude.is_match("photos/a.jpg"));
+
+        assert!(toml::from_str::<Config>("databse = \"x\"").is_err());
+        assert!(config.check().is_ok());
+        let config: Config = toml::from_str("[collections.default]\nroots = [\"a\"]").unwrap();
+        assert!(config.check().is_err());
+        // `database` would be kept in database.bin next to the default one
+        let config: Config = toml::from_str("[collections.database]").unwrap();
+        assert!(config.collections(Some("database.bin".into())).is_err());
+        let config: Config = toml::from_str(
+            "[collections.a]\ndatabase = \"x.bin\"\n[collections.b]\ndatabase = \"x.bin\"",
+        )
+        .unwrap();
+        assert!(config.collection(Some("a"), None).is_err());
+    }
+}
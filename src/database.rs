@@ -0,0 +1,544 @@
+//! The on-disk index: image embeddings and everything derived from them.
+use crate::error::{Error, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf, MAIN_SEPARATOR, MAIN_SEPARATOR_STR};
+use std::sync::Mutex;
+
+pub type Embedding = Vec<f32>;
+
+/// Format version written to `database.bin`. Older files without a version
+/// are a bare map from path to embedding, and are upgraded when loaded.
+/// Since version 2 paths are stored relative to their root.
+const DATABASE_VERSION: u32 = 2;
+
+/// A library directory. Images below it are stored relative to it, so the
+/// library can be moved, or the database shared between systems, and the
+/// root pointed at the new place with `remap`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Root {
+    pub name: String,
+    /// Absolute, in the form of the image paths.
+    pub location: String,
+}
+
+impl Root {
+    /// The part of `path` below this root, with `/` separators.
+    fn relative(&self, path: &str) -> Option<String> {
+        let rest = path.strip_prefix(self.location.as_str())?;
+        let rest = match rest.strip_prefix(MAIN_SEPARATOR) {
+            Some(rest) => rest,
+            None if self.location.ends_with(MAIN_SEPARATOR) => rest,
+            None => return None,
+        };
+        Some(rest.split(MAIN_SEPARATOR).collect::<Vec<_>>().join("/"))
+    }
+
+    pub fn contains(&self, path: &str) -> bool {
+        self.relative(path).is_some()
+    }
+
+    /// Where `path` below this root is below `to`.
+    pub fn rebase(&self, path: &str, to: &Root) -> Option<String> {
+        Some(to.join(&self.relative(path)?))
+    }
+
+    fn join(&self, relative: &str) -> String {
+        let relative = relative.replace('/', MAIN_SEPARATOR_STR);
+        if self.location.ends_with(MAIN_SEPARATOR) {
+            format!("{}{}", self.location, relative)
+        } else {
+            format!("{}{}{}", self.location, MAIN_SEPARATOR, relative)
+        }
+    }
+}
+
+/// A path as written to the file: the index of its root and the path
+/// below it, or the whole path if it is below none.
+type StoredPath = (Option<usize>, String);
+
+/// The database as written since version 2.
+#[derive(Serialize)]
+struct StoredRef<'a> {
+    version: u32,
+    model: &'a str,
+    roots: &'a [Root],
+    embeddings: BTreeMap<StoredPath, &'a Embedding>,
+    topics: &'a [Topic],
+    topic_of: BTreeMap<StoredPath, usize>,
+    tags: BTreeMap<StoredPath, &'a Vec<(String, f32)>>,
+}
+
+#[derive(Deserialize)]
+struct Stored {
+    version: u32,
+    #[serde(default = "default_model")]
+    model: String,
+    roots: Vec<Root>,
+    embeddings: BTreeMap<StoredPath, Embedding>,
+    #[serde(default)]
+    topics: Vec<Topic>,
+    #[serde(default)]
+    topic_of: BTreeMap<StoredPath, usize>,
+    #[serde(default)]
+    tags: BTreeMap<StoredPath, Vec<(String, f32)>>,
+}
+
+/// A visual topic discovered by the `cluster` command.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Topic {
+    pub label: String,
+    pub centroid: Embedding,
+}
+
+/// In memory every image is known by its full path; only the file keeps
+/// paths relative to the roots. Version 1 files are read into this directly.
+#[derive(Debug, Deserialize)]
+pub struct Database {
+    /// The model that computed the embeddings.
+    #[serde(default = "default_model")]
+    pub model: String,
+    /// Sorted by location, none below another.
+    #[serde(default)]
+    pub roots: Vec<Root>,
+    pub embeddings: BTreeMap<String, Embedding>,
+    #[serde(default)]
+    pub topics: Vec<Topic>,
+    /// Index into `topics` for every image assigned by the last clustering.
+    #[serde(default)]
+    pub topic_of: BTreeMap<String, usize>,
+    /// Tags and their probabilities from the last `tag` run.
+    #[serde(default)]
+    pub tags: BTreeMap<String, Vec<(String, f32)>>,
+    /// Maps the opaque id of every image back to its path.
+    #[serde(skip)]
+    ids: HashMap<String, String>,
+    /// Where the database was loaded from and is saved to.
+    #[serde(skip)]
+    path: PathBuf,
+}
+
+impl Default for Database {
+    fn default() -> Self {
+        Self::from_embeddings(BTreeMap::new())
+    }
+}
+
+impl Database {
+    fn from_embeddings(embeddings: BTreeMap<String, Embedding>) -> Self {
+        Self {
+            model: default_model(),
+            roots: Vec::new(),
+            embeddings,
+            topics: Vec::new(),
+            topic_of: BTreeMap::new(),
+            tags: BTreeMap::new(),
+            ids: HashMap::new(),
+            path: PathBuf::new(),
+        }
+        .with_ids()
+    }
+
+    fn with_ids(mut self) -> Self {
+        self.index_ids();
+        self
+    }
+
+    fn index_ids(&mut self) {
+        self.ids = self
+            .embeddings
+            .keys()
+            .map(|path| (image_id(path), path.clone()))
+            .collect();
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn len(&self) -> usize {
+        self.embeddings.len()
+    }
+
+    pub fn insert(&mut self, path: String, embedding: Embedding) {
+        self.ids.insert(image_id(&path), path.clone());
+        self.embeddings.insert(path, embedding);
+    }
+
+    /// Removes an image and everything recorded about it.
+    pub fn remove(&mut self, path: &str) {
+        self.ids.remove(&image_id(path));
+        self.embeddings.remove(path);
+        self.topic_of.remove(path);
+        self.tags.remove(path);
+    }
+
+    /// Registers `location` as a root, unless it is below one already.
+    /// Roots below it are merged into it. Returns the root it is under.
+    pub fn add_root(&mut self, location: &str) -> &Root {
+        let probe = Root {
+            name: String::new(),
+            location: location.to_string(),
+        };
+        if let Some(i) = self
+            .roots
+            .iter()
+            .position(|root| root.location == location || root.contains(location))
+        {
+            return &self.roots[i];
+        }
+        self.roots.retain(|root| !probe.contains(&root.location));
+        let base = Path::new(location).file_name().map_or_else(
+            || "root".to_string(),
+            |name| name.to_string_lossy().to_string(),
+        );
+        let mut name = base.clone();
+        for n in 2.. {
+            if !self.roots.iter().any(|root| root.name == name) {
+                break;
+            }
+            name = format!("{}-{}", base, n);
+        }
+        self.roots.push(Root { name, ..probe });
+        self.roots.sort_by(|a, b| a.location.cmp(&b.location));
+        let i = self.roots.iter().position(|root| root.location == location);
+        &self.roots[i.unwrap()]
+    }
+
+    /// Points the root named `name`, or at `name`, at `location`, moving
+    /// the images below it along. Returns the root as it was, as it is now
+    /// and how many images moved. `location` may not be inside or above another root, as
+    /// the images of the two could then no longer be told apart.
+    pub fn remap(&mut self, name: &str, location: &str) -> Result<(Root, Root, usize)> {
+        let i = self
+            .roots
+            .iter()
+            .position(|root| root.name == name || root.location == name)
+            .ok_or_else(|| Error::Config(format!("unknown root '{}'", name)))?;
+        let old = self.roots[i].clone();
+        let new = Root {
+            location: location.to_string(),
+            ..old.clone()
+        };
+        let overlapping = self.roots.iter().enumerate().find(|&(j, root)| {
+            j != i
+                && (root.location == location
+                    || root.contains(location)
+                    || new.contains(&root.location))
+        });
+        if let Some((_, root)) = overlapping {
+            return Err(Error::Config(format!(
+                "{} overlaps root '{}' at {}",
+                location, root.name, root.location
+            )));
+        }
+        let count = rebase(&mut self.embeddings, &old, &new);
+        rebase(&mut self.topic_of, &old, &new);
+        rebase(&mut self.tags, &old, &new);
+        self.roots[i] = new.clone();
+        self.roots.sort_by(|a, b| a.location.cmp(&b.location));
+        self.index_ids();
+        Ok((old, new, count))
+    }
+
+    fn stored_path(&self, path: &str) -> StoredPath {
+        let below = self
+            .roots
+            .iter()
+            .enumerate()
+            .find_map(|(i, root)| Some((i, root.relative(path)?)));
+        match below {
+            Some((i, relative)) => (Some(i), relative),
+            None => (None, path.to_string()),
+        }
+    }
+
+    fn encode(&self) -> std::result::Result<Vec<u8>, rmp_serde::encode::Error> {
+        let stored = StoredRef {
+            version: DATABASE_VERSION,
+            model: &self.model,
+            roots: &self.roots,
+            embeddings: self
+                .embeddings
+                .iter()
+                .map(|(path, embedding)| (self.stored_path(path), embedding))
+                .collect(),
+            topics: &self.topics,
+            topic_of: self
+                .topic_of
+                .iter()
+                .map(|(path, &topic)| (self.stored_path(path), topic))
+                .collect(),
+            tags: self
+                .tags
+                .iter()
+                .map(|(path, tags)| (self.stored_path(path), tags))
+                .collect(),
+        };
+        rmp_serde::to_vec_named(&stored)
+    }
+
+    /// Reads any version of the file. Relative paths of files before
+    /// version 2 are taken to be relative to `base`, the directory of the
+    /// file, whatever the working directory is now.
+    fn decode(bytes: &[u8], base: &Path) -> std::result::Result<Self, rmp_serde::decode::Error> {
+        if let Ok(stored) = rmp_serde::from_slice::<Stored>(bytes) {
+            if stored.version > DATABASE_VERSION {
+                return Err(rmp_serde::decode::Error::Syntax(format!(
+                    "version {} is newer than this imgfind",
+                    stored.version
+                )));
+            }
+            let roots = stored.roots;
+            let path = |(root, path): StoredPath| match root.and_then(|i| roots.get(i)) {
+                Some(root) => root.join(&path),
+                None => path,
+            };
+            let mut database = Self::from_embeddings(
+                stored
+                    .embeddings
+                    .into_iter()
+                    .map(|(p, embedding)| (path(p), embedding))
+                    .collect(),
+            );
+            database.topics = stored.topics;
+            database.topic_of = stored
+                .topic_of
+                .into_iter()
+                .map(|(p, t)| (path(p), t))
+                .collect();
+            database.tags = stored.tags.into_iter().map(|(p, t)| (path(p), t)).collect();
+            database.model = stored.model;
+            database.roots = roots;
+            return Ok(database);
+        }
+        let database = rmp_serde::from_slice::<Database>(bytes)
+            .or_else(|_| rmp_serde::from_slice(bytes).map(Database::from_embeddings))?;
+        Ok(Database {
+            embeddings: absolute_paths(database.embeddings, base),
+            topic_of: absolute_paths(database.topic_of, base),
+            tags: absolute_paths(database.tags, base),
+            ..database
+        }
+        .with_ids())
+    }
+
+    /// Looks up the path of an indexed image by its id.
+    pub fn path_of(&self, id: &str) -> Option<&String> {
+        self.ids.get(id)
+    }
+
+    /// Whether the image carries every one of `tags`.
+    pub fn has_tags(&self, path: &str, tags: &[String]) -> bool {
+        let image_tags = self.tags.get(path).map(Vec::as_slice).unwrap_or_default();
+        tags.iter()
+            .all(|tag| image_tags.iter().any(|(t, _)| t == tag))
+    }
+}
+
+fn default_model() -> String {
+    crate::model::MODEL_NAME.to_string()
+}
+
+/// `path` made absolute against the working directory, as `add` stores it.
+pub fn absolute_key(path: &str) -> String {
+    match std::path::absolute(key_path(path)) {
+        Ok(absolute) => path_key(&absolute).unwrap_or_else(|| path.to_string()),
+        Err(_) => path.to_string(),
+    }
+}
+
+/// The keys of `map` made absolute against `base`.
+fn absolute_paths<V>(map: BTreeMap<String, V>, base: &Path) -> BTreeMap<String, V> {
+    map.into_iter()
+        .map(|(path, value)| {
+            let absolute = std::path::absolute(base.join(key_path(&path)));
+            let key = absolute.ok().and_then(|absolute| path_key(&absolute));
+            (key.unwrap_or(path), value)
+        })
+        .collect()
+}
+
+/// Moves the entries of `map` below `old` to the same place below `new`.
+fn rebase<V>(map: &mut BTreeMap<String, V>, old: &Root, new: &Root) -> usize {
+    let paths: Vec<String> = map
+        .keys()
+        .filter(|path| old.contains(path))
+        .cloned()
+        .collect();
+    for path in paths.iter() {
+        let value = map.remove(path).unwrap();
+        map.insert(old.rebase(path, new).unwrap(), value);
+    }
+    paths.len()
+}
+
+/// Returns the opaque id under which an image is served over HTTP: the
+/// 64-bit FNV-1a hash of its path, so ids stay stable across restarts.
+pub fn image_id(path: &str) -> String {
+    let hash = path.bytes().fold(0xcbf2_9ce4_8422_2325u64, |hash, byte| {
+        (hash ^ byte as u64).wrapping_mul(0x0100_0000_01b3)
+    });
+    format!("{:016x}", hash)
+}
+
+/// Bytes of a path that are not UTF-8 are kept in its key as this character
+/// plus the byte, from the end of the private use area.
+#[cfg(unix)]
+const RAW_BYTE: u32 = 0x10ff00;
+
+/// The key under which the image at `path` is stored. On unix a path that
+/// is not UTF-8 still has one, from which `key_path` gets the path back;
+/// elsewhere such paths cannot be indexed.
+pub fn path_key(path: &Path) -> Option<String> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::ffi::OsStrExt;
+        let mut key = String::new();
+        for chunk in path.as_os_str().as_bytes().utf8_chunks() {
+            key.push_str(chunk.valid());
+            key.extend(
+                chunk
+                    .invalid()
+                    .iter()
+                    .filter_map(|&byte| char::from_u32(RAW_BYTE + byte as u32)),
+            );
+        }
+        Some(key)
+    }
+    #[cfg(not(unix))]
+    path.to_str().map(str::to_string)
+}
+
+/// The file an image key stands for.
+pub fn key_path(key: &str) -> PathBuf {
+    #[cfg(unix)]
+    if key.chars().any(|c| c as u32 >= RAW_BYTE) {
+        use std::os::unix::ffi::OsStringExt;
+        let mut bytes = Vec::with_capacity(key.len());
+        for c in key.chars() {
+            match (c as u32).checked_sub(RAW_BYTE) {
+                Some(byte) => bytes.push(byte as u8),
+                None => bytes.extend_from_slice(c.encode_utf8(&mut [0; 4]).as_bytes()),
+            }
+        }
+        return PathBuf::from(std::ffi::OsString::from_vec(bytes));
+    }
+    PathBuf::from(key)
+}
+
+pub fn load_database(path: &Path) -> Result<Database> {
+    let database = match std::fs::read(path) {
+        Ok(bytes) => {
+            Database::decode(&bytes, path.parent().unwrap_or(Path::new(""))).map_err(|e| {
+                Error::Database {
+                    path: path.to_path_buf(),
+                    message: e.to_string(),
+                }
+            })?
+        }
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            // commands run from the wrong directory should not look like an
+            // empty library without saying so
+            log::info!("no database at {}, starting an empty one", path.display());
+            Database::default()
+        }
+        Err(e) => return Err(Error::io(path, e)),
+    };
+    Ok(Database {
+        path: path.to_path_buf(),
+        ..database
+    })
+}
+
+pub fn save_database(database: &Database) -> Result<()> {
+    // `serve` may save from a job and the watcher at the same time
+    static SAVING: Mutex<()> = Mutex::new(());
+    let _saving = SAVING.lock().unwrap();
+    let path = database.path();
+    if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+        std::fs::create_dir_all(dir).map_err(|e| Error::io(dir, e))?;
+    }
+    let bytes = database.encode().map_err(|e| Error::Database {
+        path: path.to_path_buf(),
+        message: e.to_string(),
+    })?;
+    std::fs::write(path, bytes).map_err(|e| Error::io(path, e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_load_unversioned() {
+        let mut embeddings = BTreeMap::new();
+        embeddings.insert("a.jpg".to_string(), vec![1.0f32, 0.0]);
+        let file = std::env::temp_dir().join(format!("imgfind-{}.bin", std::process::id()));
+        std::fs::write(&file, rmp_serde::to_vec(&embeddings).unwrap()).unwrap();
+        let database = load_database(&file).unwrap();
+        // relative to the database, not to where the tests run
+        let path = path_key(&std::env::temp_dir().join("a.jpg")).unwrap();
+        assert_eq!(database.embeddings[&path], vec![1.0, 0.0]);
+        assert_eq!(database.path_of(&image_id(&path)), Some(&path));
+
+        save_database(&database).unwrap();
+        let database = load_database(&file).unwrap();
+        std::fs::remove_file(&file).unwrap();
+        assert_eq!(database.embeddings[&path], vec![1.0, 0.0]);
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_roots() {
+        let mut database = Database::default();
+        database.insert("/mnt/old/photos/2020/a.jpg".to_string(), vec![1.0]);
+        database.insert("/home/b.jpg".to_string(), vec![0.5]);
+        database.add_root("/mnt/old/photos/2020");
+        assert_eq!(database.add_root("/mnt/old/photos").name, "photos");
+        assert_eq!(database.add_root("/mnt/old/photos/2021").name, "photos");
+        assert_eq!(database.roots.len(), 1);
+        assert_eq!(
+            database.stored_path("/mnt/old/photos/2020/a.jpg"),
+            (Some(0), "2020/a.jpg".to_string())
+        );
+        assert_eq!(
+            database.stored_path("/home/b.jpg"),
+            (None, "/home/b.jpg".to_string())
+        );
+
+        let (old, new, moved) = database.remap("photos", "/media/photos").unwrap();
+        assert_eq!((old.location.as_str(), moved), ("/mnt/old/photos", 1));
+        assert_eq!(new.location, "/media/photos");
+        let mut database = Database::decode(&database.encode().unwrap(), Path::new("")).unwrap();
+        assert_eq!(database.roots[0].location, "/media/photos");
+        assert!(database.embeddings.contains_key("/media/photos/2020/a.jpg"));
+        assert!(database.embeddings.contains_key("/home/b.jpg"));
+        assert!(database
+            .path_of(&image_id("/media/photos/2020/a.jpg"))
+            .is_some());
+        assert!(Database::default().remap("photos", "/x").is_err());
+
+        database.add_root("/home/me");
+        assert!(database.remap("photos", "/home/me/photos").is_err());
+        assert!(database.remap("photos", "/home").is_err());
+        assert!(database.remap("photos", "/home/me").is_err());
+        assert_eq!(database.roots[0].location, "/home/me");
+        assert_eq!(database.roots[1].location, "/media/photos");
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_path_key() {
+        use std::os::unix::ffi::OsStrExt;
+        let path = Path::new(std::ffi::OsStr::from_bytes(b"photos/caf\xe9.jpg"));
+        let key = path_key(path).unwrap();
+        assert_ne!(key, path.to_string_lossy());
+        assert_eq!(key_path(&key), path);
+        assert_eq!(
+            path_key(Path::new("photos/café.jpg")).unwrap(),
+            "photos/café.jpg"
+        );
+        assert_eq!(key_path("photos/café.jpg"), Path::new("photos/café.jpg"));
+    }
+}
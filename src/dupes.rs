@@ -0,0 +1,200 @@
+use crate::database::Embedding;
+use crate::{dot_product, get_extension, load_image};
+use serde::Serialize;
+use std::collections::BTreeMap;
+
+/// Maximum hamming distance between two difference hashes to count as the same picture.
+const DHASH_MAX_DISTANCE: u32 = 10;
+
+#[derive(Debug, Clone, Serialize)]
+pub struct DupeImage {
+    pub id: String,
+    pub path: String,
+    pub size: Option<u64>,
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+}
+
+/// Zero or less would pair every image with every other one.
+pub fn valid_threshold(threshold: f32) -> bool {
+    threshold > 0.0 && threshold <= 1.0
+}
+
+pub fn parse_threshold(s: &str) -> Result<f32, String> {
+    let threshold: f32 = s.parse().map_err(|e| format!("{}", e))?;
+    if valid_threshold(threshold) {
+        Ok(threshold)
+    } else {
+        Err(format!("{} is not in (0, 1]", threshold))
+    }
+}
+
+pub struct UnionFind {
+    parent: Vec<usize>,
+}
+
+impl UnionFind {
+    pub fn new(len: usize) -> Self {
+        Self {
+            parent: (0..len).collect(),
+        }
+    }
+
+    pub fn find(&mut self, mut x: usize) -> usize {
+        while self.parent[x] != x {
+            self.parent[x] = self.parent[self.parent[x]];
+            x = self.parent[x];
+        }
+        x
+    }
+
+    pub fn union(&mut self, a: usize, b: usize) {
+        let (a, b) = (self.find(a), self.find(b));
+        if a != b {
+            self.parent[a.max(b)] = a.min(b);
+        }
+    }
+}
+
+fn similar_pairs(embeddings: &[&Vec<f32>], threshold: f32) -> Vec<(usize, usize)> {
+    let threads = crate::thread_count();
+    std::thread::scope(|s| {
+        let handles: Vec<_> = (0..threads)
+            .map(|t| {
+                s.spawn(move || {
+                    let mut pairs = Vec::new();
+                    // interleave rows so every thread gets a similar share of the triangle
+                    for i in (t..embeddings.len()).step_by(threads) {
+                        for j in i + 1..embeddings.len() {
+                            if dot_product(embeddings[i], embeddings[j]) >= threshold {
+                                pairs.push((i, j));
+                            }
+                        }
+                    }
+                    pairs
+                })
+            })
+            .collect();
+        handles
+            .into_iter()
+            .flat_map(|h| h.join().expect("dupes thread panicked"))
+            .collect()
+    })
+}
+
+/// Difference hash of the image shrunk to 9x8 grayscale.
+pub fn dhash(path: &str) -> crate::error::Result<u64> {
+    let img = load_image(path)?
+        .resize_exact(9, 8, image::imageops::FilterType::Triangle)
+        .to_luma8();
+    let mut hash = 0u64;
+    for y in 0..8 {
+        for x in 0..8 {
+            let bit = img.get_pixel(x, y)[0] > img.get_pixel(x + 1, y)[0];
+            hash = hash << 1 | bit as u64;
+        }
+    }
+    Ok(hash)
+}
+
+#[cfg(feature = "heif")]
+fn heif_dimensions(path: &str) -> Option<(u32, u32)> {
+    use libheif_rs::HeifContext;
+    let path = crate::database::key_path(path);
+    let bytes;
+    // libheif only opens files by a UTF-8 name, others are read into memory
+    let ctx = match path.to_str() {
+        Some(name) => HeifContext::read_from_file(name).ok()?,
+        None => {
+            bytes = std::fs::read(&path).ok()?;
+            HeifContext::read_from_bytes(&bytes).ok()?
+        }
+    };
+    let handle = ctx.primary_image_handle().ok()?;
+    Some((handle.width(), handle.height()))
+}
+
+#[cfg(not(feature = "heif"))]
+fn heif_dimensions(_path: &str) -> Option<(u32, u32)> {
+    None
+}
+
+pub fn image_dimensions(path: &str) -> Option<(u32, u32)> {
+    let extension = get_extension(path);
+    if extension == "heic" || extension == "heif" {
+        heif_dimensions(path)
+    } else {
+        image::image_dimensions(crate::database::key_path(path)).ok()
+    }
+}
+
+fn describe(path: &str) -> DupeImage {
+    let (width, height) = image_dimensions(path).unzip();
+    DupeImage {
+        id: crate::database::image_id(path),
+        path: path.to_string(),
+        size: std::fs::metadata(crate::database::key_path(path))
+            .map(|m| m.len())
+            .ok(),
+        width,
+        height,
+    }
+}
+
+/// Groups are ordered largest first, each one best shot first.
+pub fn find_duplicates(
+    embeddings: &BTreeMap<String, Embedding>,
+    threshold: f32,
+    phash: bool,
+) -> Vec<Vec<DupeImage>> {
+    let (paths, embeddings): (Vec<_>, Vec<_>) = embeddings.iter().unzip();
+    let mut pairs = similar_pairs(&embeddings, threshold);
+    if phash {
+        let mut hashes = std::collections::HashMap::new();
+        pairs.retain(|&(i, j)| {
+            let mut hash = |k: usize| *hashes.entry(k).or_insert_with(|| dhash(paths[k]).ok());
+            match (hash(i), hash(j)) {
+                (Some(a), Some(b)) => (a ^ b).count_ones() <= DHASH_MAX_DISTANCE,
+                _ => false,
+            }
+        });
+    }
+    let mut union_find = UnionFind::new(paths.len());
+    for &(i, j) in pairs.iter() {
+        union_find.union(i, j);
+    }
+    let mut members: Vec<usize> = pairs.iter().flat_map(|&(i, j)| [i, j]).collect();
+    members.sort_unstable();
+    members.dedup();
+    let mut groups = std::collections::BTreeMap::<usize, Vec<usize>>::new();
+    for k in members {
+        groups.entry(union_find.find(k)).or_default().push(k);
+    }
+    let mut groups: Vec<Vec<DupeImage>> = groups
+        .into_values()
+        .map(|group| {
+            let mut group: Vec<DupeImage> = group.into_iter().map(|k| describe(paths[k])).collect();
+            group.sort_by_key(|image| {
+                let pixels = image.width.unwrap_or(0) as u64 * image.height.unwrap_or(0) as u64;
+                std::cmp::Reverse((pixels, image.size.unwrap_or(0)))
+            });
+            group
+        })
+        .collect();
+    groups.sort_by_key(|group| std::cmp::Reverse(group.len()));
+    groups
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_similar_pairs() {
+        let a = vec![1.0, 0.0];
+        let b = vec![0.99, 0.141];
+        let c = vec![0.0, 1.0];
+        let pairs = similar_pairs(&[&a, &b, &c], 0.95);
+        assert_eq!(pairs, vec![(0, 1)]);
+    }
+}
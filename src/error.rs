@@ -0,0 +1,187 @@
+//! Errors of the commands and of indexing single images. Failures tied to
+//! one file carry its path, so a run can log them and go on with the rest.
+use serde::{Deserialize, Serialize};
+use std::fmt::Display;
+use std::path::PathBuf;
+
+pub type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+/// What went wrong, as recorded in failure reports.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ErrorKind {
+    Io,
+    Decode,
+    Unsupported,
+    Model,
+    Database,
+    Query,
+    Config,
+}
+
+impl Display for ErrorKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            ErrorKind::Io => "io",
+            ErrorKind::Decode => "decode",
+            ErrorKind::Unsupported => "unsupported",
+            ErrorKind::Model => "model",
+            ErrorKind::Database => "database",
+            ErrorKind::Query => "query",
+            ErrorKind::Config => "config",
+        })
+    }
+}
+
+#[derive(Debug)]
+pub enum Error {
+    /// A file or directory could not be read or written.
+    Io {
+        path: Option<PathBuf>,
+        source: std::io::Error,
+    },
+    /// The file is damaged or not what it claims to be.
+    Decode { path: String, source: BoxError },
+    /// An image format this build cannot read, such as HEIF without the
+    /// heif feature.
+    Unsupported { path: String, message: String },
+    /// Loading or running the model or the tokenizer failed.
+    Model(BoxError),
+    /// A database or sidecar file exists but cannot be decoded.
+    Database { path: PathBuf, message: String },
+    /// The search text could not be encoded.
+    Query(String),
+    /// Invalid settings or arguments.
+    Config(String),
+}
+
+impl Error {
+    pub fn io(path: impl Into<PathBuf>, source: std::io::Error) -> Self {
+        Self::Io {
+            path: Some(path.into()),
+            source,
+        }
+    }
+
+    /// Classifies an error of the image crate while loading `path`.
+    pub fn image(path: &str, error: image::ImageError) -> Self {
+        match error {
+            image::ImageError::IoError(source) => Self::io(path, source),
+            image::ImageError::Unsupported(e) => Self::Unsupported {
+                path: path.to_string(),
+                message: e.to_string(),
+            },
+            e => Self::Decode {
+                path: path.to_string(),
+                source: Box::new(e),
+            },
+        }
+    }
+
+    pub fn kind(&self) -> ErrorKind {
+        match self {
+            Error::Io { .. } => ErrorKind::Io,
+            Error::Decode { .. } => ErrorKind::Decode,
+            Error::Unsupported { .. } => ErrorKind::Unsupported,
+            Error::Model(_) => ErrorKind::Model,
+            Error::Database { .. } => ErrorKind::Database,
+            Error::Query(_) => ErrorKind::Query,
+            Error::Config(_) => ErrorKind::Config,
+        }
+    }
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::Io {
+                path: Some(path),
+                source,
+            } => write!(f, "{}: {}", path.display(), source),
+            Error::Io { path: None, source } => write!(f, "{}", source),
+            Error::Decode { path, source } => write!(f, "failed to decode {}: {}", path, source),
+            Error::Unsupported { path, message } => write!(f, "{}: {}", path, message),
+            Error::Model(e) => write!(f, "model error: {}", e),
+            Error::Database { path, message } => {
+                write!(f, "invalid {}: {}", path.display(), message)
+            }
+            Error::Query(message) | Error::Config(message) => f.write_str(message),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io { source, .. } => Some(source),
+            Error::Decode { source, .. } | Error::Model(source) => Some(source.as_ref()),
+            _ => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(source: std::io::Error) -> Self {
+        Self::Io { path: None, source }
+    }
+}
+
+impl From<candle_core::Error> for Error {
+    fn from(error: candle_core::Error) -> Self {
+        Self::Model(Box::new(error))
+    }
+}
+
+impl From<notify::Error> for Error {
+    fn from(error: notify::Error) -> Self {
+        match error.kind {
+            notify::ErrorKind::Io(source) => Self::Io {
+                path: error.paths.into_iter().next(),
+                source,
+            },
+            _ => Self::Config(error.to_string()),
+        }
+    }
+}
+
+impl From<String> for Error {
+    fn from(message: String) -> Self {
+        Self::Config(message)
+    }
+}
+
+impl From<&str> for Error {
+    fn from(message: &str) -> Self {
+        Self::Config(message.to_string())
+    }
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_error_kinds() {
+        let missing = image::ImageError::IoError(std::io::ErrorKind::NotFound.into());
+        let error = Error::image("a.jpg", missing);
+        assert_eq!(error.kind(), ErrorKind::Io);
+        assert!(error.to_string().starts_with("a.jpg: "));
+
+        let unsupported =
+            image::ImageError::Unsupported(image::error::UnsupportedError::from_format_and_kind(
+                image::error::ImageFormatHint::Unknown,
+                image::error::UnsupportedErrorKind::Format(image::error::ImageFormatHint::Unknown),
+            ));
+        assert_eq!(
+            Error::image("a.jpg", unsupported).kind(),
+            ErrorKind::Unsupported
+        );
+        assert_eq!(Error::from("bad").to_string(), "bad");
+        assert_eq!(
+            serde_json::to_string(&ErrorKind::Decode).unwrap(),
+            "\"decode\""
+        );
+    }
+}
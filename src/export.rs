@@ -0,0 +1,704 @@
+use crate::database::{self, Database, Embedding};
+use crate::error::{Error, Result};
+use serde::{Deserialize, Serialize};
+use std::io::{BufRead, Read, Write};
+use std::path::{Path, PathBuf};
+
+#[derive(Debug, Clone, Copy, PartialEq, clap::ValueEnum)]
+pub enum ExportFormat {
+    Npy,
+    Npz,
+    Safetensors,
+    Parquet,
+    Jsonl,
+}
+
+impl ExportFormat {
+    pub fn of(path: &Path) -> Option<Self> {
+        let extension = path.extension()?.to_string_lossy().to_lowercase();
+        Some(match extension.as_str() {
+            "npy" => Self::Npy,
+            "npz" => Self::Npz,
+            "safetensors" => Self::Safetensors,
+            "parquet" => Self::Parquet,
+            "jsonl" | "ndjson" => Self::Jsonl,
+            _ => return None,
+        })
+    }
+}
+
+#[derive(Debug, Default, PartialEq)]
+pub struct Embeddings {
+    pub model: Option<String>,
+    pub paths: Vec<String>,
+    pub vectors: Vec<Embedding>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Line<'a> {
+    path: std::borrow::Cow<'a, str>,
+    embedding: std::borrow::Cow<'a, [f32]>,
+}
+
+fn invalid(path: &Path, message: impl Into<String>) -> Error {
+    Error::Decode {
+        path: path.to_string_lossy().to_string(),
+        source: message.into().into(),
+    }
+}
+
+fn paths_file(path: &Path) -> PathBuf {
+    path.with_extension("paths.npy")
+}
+
+/// On unix, bytes of a name that are not UTF-8 are written as `\xNN` and
+/// backslashes as `\\`, so Python's `codecs.escape_decode` gets them back.
+fn exported_path(key: &str) -> String {
+    #[cfg(unix)]
+    {
+        use std::os::unix::ffi::OsStrExt;
+        let path = database::key_path(key);
+        let mut exported = String::with_capacity(key.len());
+        for chunk in path.as_os_str().as_bytes().utf8_chunks() {
+            exported.push_str(&chunk.valid().replace('\\', "\\\\"));
+            for byte in chunk.invalid() {
+                exported.push_str(&format!("\\x{:02x}", byte));
+            }
+        }
+        exported
+    }
+    #[cfg(not(unix))]
+    key.to_string()
+}
+
+fn imported_key(path: &str) -> String {
+    #[cfg(unix)]
+    {
+        use std::os::unix::ffi::OsStrExt;
+        let hex = |digit: u8| (digit as char).to_digit(16).map(|d| d as u8);
+        let mut bytes = Vec::with_capacity(path.len());
+        let mut rest = path.as_bytes();
+        while let [byte, tail @ ..] = rest {
+            rest = tail;
+            match (byte, rest) {
+                (b'\\', [b'\\', tail @ ..]) => rest = tail,
+                (b'\\', [b'x', high, low, tail @ ..]) => {
+                    if let (Some(high), Some(low)) = (hex(*high), hex(*low)) {
+                        bytes.push(high << 4 | low);
+                        rest = tail;
+                        continue;
+                    }
+                }
+                _ => {}
+            }
+            bytes.push(*byte);
+        }
+        let path = Path::new(std::ffi::OsStr::from_bytes(&bytes));
+        database::path_key(path).unwrap_or_else(|| path.to_string_lossy().to_string())
+    }
+    #[cfg(not(unix))]
+    path.to_string()
+}
+
+pub fn export(database: &Database, path: &Path, format: ExportFormat) -> Result<()> {
+    let write =
+        |path: &Path, bytes: &[u8]| std::fs::write(path, bytes).map_err(|e| Error::io(path, e));
+    let dimension = database.embeddings.values().next().map_or(0, Vec::len);
+    let exported: Vec<String> = database
+        .embeddings
+        .keys()
+        .map(|key| exported_path(key))
+        .collect();
+    let paths: Vec<&str> = exported.iter().map(String::as_str).collect();
+    let vectors = || database.embeddings.values().map(Vec::as_slice);
+    match format {
+        ExportFormat::Npy => {
+            write(path, &npy::floats(vectors(), paths.len(), dimension))?;
+            write(&paths_file(path), &npy::strings(&paths, &[paths.len()]))
+        }
+        ExportFormat::Npz => {
+            let file = std::fs::File::create(path).map_err(|e| Error::io(path, e))?;
+            let mut zip = zip::ZipWriter::new(std::io::BufWriter::new(file));
+            let members = [
+                (
+                    "embeddings.npy",
+                    npy::floats(vectors(), paths.len(), dimension),
+                ),
+                ("paths.npy", npy::strings(&paths, &[paths.len()])),
+                ("model.npy", npy::strings(&[&database.model], &[])),
+            ];
+            for (name, bytes) in members {
+                // stored like `numpy.savez` does, so the arrays can be mapped
+                let options = zip::write::FileOptions::default()
+                    .compression_method(zip::CompressionMethod::Stored)
+                    .large_file(bytes.len() as u64 >= u32::MAX as u64);
+                zip.start_file(name, options)
+                    .map_err(|e| Error::io(path, e.into()))?;
+                zip.write_all(&bytes).map_err(|e| Error::io(path, e))?;
+            }
+            zip.finish().map_err(|e| Error::io(path, e.into()))?;
+            Ok(())
+        }
+        ExportFormat::Safetensors => {
+            let data: Vec<u8> = vectors().flatten().flat_map(|x| x.to_le_bytes()).collect();
+            let view = safetensors::tensor::TensorView::new(
+                safetensors::Dtype::F32,
+                vec![paths.len(), dimension],
+                &data,
+            )
+            .map_err(|e| invalid(path, e.to_string()))?;
+            let metadata = [
+                ("model".to_string(), database.model.clone()),
+                ("paths".to_string(), serde_json::to_string(&paths).unwrap()),
+            ];
+            let bytes =
+                safetensors::tensor::serialize([("embeddings", view)], &Some(metadata.into()))
+                    .map_err(|e| invalid(path, e.to_string()))?;
+            write(path, &bytes)
+        }
+        #[cfg(feature = "parquet")]
+        ExportFormat::Parquet => parquet::write(path, &database.model, &paths, vectors()),
+        #[cfg(not(feature = "parquet"))]
+        ExportFormat::Parquet => Err(Error::Config("parquet support not enabled".to_string())),
+        ExportFormat::Jsonl => {
+            let file = std::fs::File::create(path).map_err(|e| Error::io(path, e))?;
+            let mut out = std::io::BufWriter::new(file);
+            for (path, embedding) in exported.iter().zip(database.embeddings.values()) {
+                let line = Line {
+                    path: path.into(),
+                    embedding: embedding.into(),
+                };
+                serde_json::to_writer(&mut out, &line).map_err(std::io::Error::from)?;
+                writeln!(out)?;
+            }
+            out.flush().map_err(|e| Error::io(path, e))
+        }
+    }
+}
+
+pub fn import(path: &Path, format: ExportFormat) -> Result<Embeddings> {
+    let read = |path: &Path| std::fs::read(path).map_err(|e| Error::io(path, e));
+    let embeddings = match format {
+        ExportFormat::Npy => Embeddings {
+            model: None,
+            vectors: npy::read_floats(path, &read(path)?)?,
+            paths: npy::read_strings(&paths_file(path), &read(&paths_file(path))?)?,
+        },
+        ExportFormat::Npz => {
+            let file = std::fs::File::open(path).map_err(|e| Error::io(path, e))?;
+            let mut zip = zip::ZipArchive::new(std::io::BufReader::new(file))
+                .map_err(|e| invalid(path, e.to_string()))?;
+            let mut member = |name: &str| -> Result<Option<Vec<u8>>> {
+                let mut file = match zip.by_name(name) {
+                    Ok(file) => file,
+                    Err(zip::result::ZipError::FileNotFound) => return Ok(None),
+                    Err(e) => return Err(invalid(path, e.to_string())),
+                };
+                let mut bytes = Vec::new();
+                file.read_to_end(&mut bytes)
+                    .map_err(|e| Error::io(path, e))?;
+                Ok(Some(bytes))
+            };
+            let required = |bytes: Option<Vec<u8>>, name: &str| {
+                bytes.ok_or_else(|| invalid(path, format!("no {} array", name)))
+            };
+            let vectors = required(member("embeddings.npy")?, "embeddings")?;
+            let paths = required(member("paths.npy")?, "paths")?;
+            let model = match member("model.npy")? {
+                Some(bytes) => npy::read_strings(path, &bytes)?.pop(),
+                None => None,
+            };
+            Embeddings {
+                model,
+                vectors: npy::read_floats(path, &vectors)?,
+                paths: npy::read_strings(path, &paths)?,
+            }
+        }
+        ExportFormat::Safetensors => {
+            let bytes = read(path)?;
+            let (_, metadata) = safetensors::SafeTensors::read_metadata(&bytes)
+                .map_err(|e| invalid(path, e.to_string()))?;
+            let metadata = metadata.metadata().clone().unwrap_or_default();
+            let paths = metadata
+                .get("paths")
+                .ok_or_else(|| invalid(path, "no paths in the metadata"))?;
+            let tensors = safetensors::SafeTensors::deserialize(&bytes)
+                .map_err(|e| invalid(path, e.to_string()))?;
+            let tensor = tensors
+                .tensor("embeddings")
+                .map_err(|e| invalid(path, e.to_string()))?;
+            let float64 = match tensor.dtype() {
+                safetensors::Dtype::F32 => false,
+                safetensors::Dtype::F64 => true,
+                dtype => return Err(invalid(path, format!("embeddings of type {:?}", dtype))),
+            };
+            let [rows, columns] = tensor.shape() else {
+                return Err(invalid(path, "embeddings are not a matrix"));
+            };
+            Embeddings {
+                model: metadata.get("model").cloned(),
+                paths: serde_json::from_str(paths).map_err(|e| invalid(path, e.to_string()))?,
+                vectors: npy::rows(tensor.data(), float64, *rows, *columns),
+            }
+        }
+        #[cfg(feature = "parquet")]
+        ExportFormat::Parquet => parquet::read(path)?,
+        #[cfg(not(feature = "parquet"))]
+        ExportFormat::Parquet => {
+            return Err(Error::Config("parquet support not enabled".to_string()))
+        }
+        ExportFormat::Jsonl => {
+            let file = std::fs::File::open(path).map_err(|e| Error::io(path, e))?;
+            let mut embeddings = Embeddings::default();
+            for (i, line) in std::io::BufReader::new(file).lines().enumerate() {
+                let line = line.map_err(|e| Error::io(path, e))?;
+                if line.trim().is_empty() {
+                    continue;
+                }
+                let line: Line = serde_json::from_str(&line)
+                    .map_err(|e| invalid(path, format!("line {}: {}", i + 1, e)))?;
+                embeddings.paths.push(line.path.into_owned());
+                embeddings.vectors.push(line.embedding.into_owned());
+            }
+            embeddings
+        }
+    };
+    if embeddings.paths.len() != embeddings.vectors.len() {
+        return Err(invalid(
+            path,
+            format!(
+                "{} paths for {} embeddings",
+                embeddings.paths.len(),
+                embeddings.vectors.len()
+            ),
+        ));
+    }
+    Ok(embeddings)
+}
+
+/// Nothing is merged unless every embedding fits the database. Returns how
+/// many were added and how many skipped as indexed already.
+pub fn merge(
+    database: &mut Database,
+    embeddings: Embeddings,
+    overwrite: bool,
+) -> Result<(usize, usize)> {
+    match embeddings.model.as_deref() {
+        Some(model) if model != database.model => {
+            return Err(Error::Config(format!(
+                "the embeddings are from {}, the database holds {}",
+                model, database.model
+            )))
+        }
+        Some(_) => {}
+        None => log::warn!(
+            "the file does not name its model, assuming {}",
+            database.model
+        ),
+    }
+    let dimension = database
+        .embeddings
+        .values()
+        .next()
+        .map_or(crate::model::EMBEDDING_DIM, Vec::len);
+    for (path, vector) in embeddings.paths.iter().zip(embeddings.vectors.iter()) {
+        if vector.len() != dimension {
+            return Err(Error::Config(format!(
+                "the embedding of {} has {} dimensions, the database {}",
+                path,
+                vector.len(),
+                dimension
+            )));
+        }
+        if !vector.iter().all(|x| x.is_finite()) || vector.iter().all(|&x| x == 0.0) {
+            return Err(Error::Config(format!(
+                "the embedding of {} is invalid",
+                path
+            )));
+        }
+    }
+    let (mut added, mut skipped) = (0, 0);
+    let mut directories = std::collections::BTreeSet::new();
+    for (path, vector) in embeddings.paths.into_iter().zip(embeddings.vectors) {
+        let path = database::absolute_key(&imported_key(&path));
+        if !overwrite && database.embeddings.contains_key(&path) {
+            skipped += 1;
+            continue;
+        }
+        if let Some(directory) = database::key_path(&path).parent() {
+            directories.extend(database::path_key(directory));
+        }
+        database.insert(path, crate::normalize(&vector));
+        added += 1;
+    }
+    // registered like `add` does, so that `remap` can move them later
+    for directory in directories {
+        database.add_root(&directory);
+    }
+    Ok((added, skipped))
+}
+
+/// Just enough of the NumPy file format for little-endian float matrices
+/// and unicode string arrays.
+mod npy {
+    use super::invalid;
+    use crate::error::Result;
+    use std::path::Path;
+
+    const MAGIC: &[u8] = b"\x93NUMPY";
+
+    pub(super) fn array(descr: &str, shape: &[usize], data: &[u8]) -> Vec<u8> {
+        let shape = match shape {
+            [n] => format!("({},)", n),
+            shape => format!(
+                "({})",
+                shape
+                    .iter()
+                    .map(|n| n.to_string())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ),
+        };
+        let mut header = format!(
+            "{{'descr': '{}', 'fortran_order': False, 'shape': {}, }}",
+            descr, shape
+        );
+        // the data starts at a multiple of 64 bytes
+        let unpadded = MAGIC.len() + 4 + header.len() + 1;
+        header.push_str(&" ".repeat((64 - unpadded % 64) % 64));
+        header.push('\n');
+        let mut bytes = Vec::with_capacity(MAGIC.len() + 4 + header.len() + data.len());
+        bytes.extend_from_slice(MAGIC);
+        bytes.extend_from_slice(&[1, 0]);
+        bytes.extend_from_slice(&(header.len() as u16).to_le_bytes());
+        bytes.extend_from_slice(header.as_bytes());
+        bytes.extend_from_slice(data);
+        bytes
+    }
+
+    pub fn floats<'a>(
+        vectors: impl Iterator<Item = &'a [f32]>,
+        rows: usize,
+        columns: usize,
+    ) -> Vec<u8> {
+        let data: Vec<u8> = vectors.flatten().flat_map(|x| x.to_le_bytes()).collect();
+        array("<f4", &[rows, columns], &data)
+    }
+
+    pub fn strings(strings: &[&str], shape: &[usize]) -> Vec<u8> {
+        let width = strings
+            .iter()
+            .map(|s| s.chars().count())
+            .max()
+            .unwrap_or(0)
+            .max(1);
+        let mut data = Vec::with_capacity(strings.len() * width * 4);
+        for s in strings {
+            let len = s.chars().count();
+            data.extend(s.chars().flat_map(|c| (c as u32).to_le_bytes()));
+            data.resize(data.len() + (width - len) * 4, 0);
+        }
+        array(&format!("<U{}", width), shape, &data)
+    }
+
+    struct Array<'a> {
+        descr: String,
+        shape: Vec<usize>,
+        data: &'a [u8],
+    }
+
+    fn header_value<'a>(header: &'a str, key: &str) -> Option<&'a str> {
+        let start = header.find(&format!("'{}'", key))? + key.len() + 2;
+        let value = header[start..].trim_start().strip_prefix(':')?.trim_start();
+        let end = match value.chars().next()? {
+            '(' => value.find(')')? + 1,
+            '\'' => value[1..].find('\'')? + 2,
+            _ => value.find([',', '}'])?,
+        };
+        Some(value[..end].trim())
+    }
+
+    fn parse<'a>(path: &Path, bytes: &'a [u8]) -> Result<Array<'a>> {
+        let bad = || invalid(path, "not a npy array");
+        if !bytes.starts_with(MAGIC) || bytes.len() < 10 {
+            return Err(bad());
+        }
+        let (len, start) = match bytes[6] {
+            1 => (u16::from_le_bytes([bytes[8], bytes[9]]) as usize, 10),
+            _ if bytes.len() >= 12 => (
+                u32::from_le_bytes(bytes[8..12].try_into().unwrap()) as usize,
+                12,
+            ),
+            _ => return Err(bad()),
+        };
+        let header = bytes.get(start..start + len).ok_or_else(bad)?;
+        let header = std::str::from_utf8(header).map_err(|_| bad())?;
+        if header_value(header, "fortran_order") != Some("False") {
+            return Err(invalid(path, "arrays in fortran order are not supported"));
+        }
+        let descr = header_value(header, "descr").ok_or_else(bad)?;
+        let shape = header_value(header, "shape").ok_or_else(bad)?;
+        let shape = shape
+            .trim_matches(['(', ')'])
+            .split(',')
+            .map(str::trim)
+            .filter(|n| !n.is_empty())
+            .map(|n| n.parse().map_err(|_| bad()))
+            .collect::<Result<Vec<usize>>>()?;
+        Ok(Array {
+            descr: descr.trim_matches('\'').to_string(),
+            shape,
+            data: &bytes[start + len..],
+        })
+    }
+
+    pub fn rows(data: &[u8], float64: bool, rows: usize, columns: usize) -> Vec<Vec<f32>> {
+        let values: Vec<f32> = if float64 {
+            data.chunks_exact(8)
+                .map(|b| f64::from_le_bytes(b.try_into().unwrap()) as f32)
+                .collect()
+        } else {
+            data.chunks_exact(4)
+                .map(|b| f32::from_le_bytes(b.try_into().unwrap()))
+                .collect()
+        };
+        values
+            .chunks(columns.max(1))
+            .take(rows)
+            .map(<[f32]>::to_vec)
+            .collect()
+    }
+
+    /// `None` if the array does not fit into memory at all.
+    fn byte_len(dims: impl IntoIterator<Item = usize>) -> Option<usize> {
+        dims.into_iter()
+            .try_fold(1usize, |len, dim| len.checked_mul(dim))
+    }
+
+    pub fn read_floats(path: &Path, bytes: &[u8]) -> Result<Vec<Vec<f32>>> {
+        let array = parse(path, bytes)?;
+        let float64 = match array.descr.as_str() {
+            "<f4" => false,
+            "<f8" => true,
+            descr => return Err(invalid(path, format!("embeddings of type {}", descr))),
+        };
+        let [rows, columns] = array.shape[..] else {
+            return Err(invalid(path, "embeddings are not a matrix"));
+        };
+        let size = if float64 { 8 } else { 4 };
+        byte_len([rows, columns, size])
+            .filter(|&len| len <= array.data.len())
+            .ok_or_else(|| invalid(path, "the file is cut short"))?;
+        Ok(self::rows(array.data, float64, rows, columns))
+    }
+
+    pub fn read_strings(path: &Path, bytes: &[u8]) -> Result<Vec<String>> {
+        let array = parse(path, bytes)?;
+        // bytes per string, four for every UTF-32 character
+        let width = array
+            .descr
+            .strip_prefix("<U")
+            .and_then(|width| width.parse::<usize>().ok())
+            .filter(|&width| width > 0)
+            .and_then(|width| width.checked_mul(4))
+            .ok_or_else(|| invalid(path, format!("strings of type {}", array.descr)))?;
+        let len = byte_len(array.shape.iter().copied().chain([width]))
+            .filter(|&len| len <= array.data.len())
+            .ok_or_else(|| invalid(path, "the file is cut short"))?;
+        Ok(array.data[..len]
+            .chunks_exact(width)
+            .map(|string| {
+                string
+                    .chunks_exact(4)
+                    .map(|c| u32::from_le_bytes(c.try_into().unwrap()))
+                    .take_while(|&c| c != 0)
+                    .map(|c| char::from_u32(c).unwrap_or(char::REPLACEMENT_CHARACTER))
+                    .collect()
+            })
+            .collect())
+    }
+}
+
+#[cfg(feature = "parquet")]
+mod parquet {
+    use super::{invalid, Embeddings};
+    use crate::error::{Error, Result};
+    use parquet::data_type::{ByteArray, ByteArrayType, FloatType};
+    use parquet::file::properties::WriterProperties;
+    use parquet::file::reader::{FileReader, SerializedFileReader};
+    use parquet::file::writer::SerializedFileWriter;
+    use parquet::format::KeyValue;
+    use parquet::record::{Field, RowAccessor};
+    use std::path::Path;
+    use std::sync::Arc;
+
+    const SCHEMA: &str = "message embeddings {
+        required binary path (UTF8);
+        required group embedding (LIST) {
+            repeated group list {
+                required float element;
+            }
+        }
+    }";
+    const ROW_GROUP: usize = 10_000;
+
+    pub fn write<'a>(
+        path: &Path,
+        model: &str,
+        paths: &[&str],
+        vectors: impl Iterator<Item = &'a [f32]>,
+    ) -> Result<()> {
+        let error = |e: parquet::errors::ParquetError| invalid(path, e.to_string());
+        let schema = Arc::new(parquet::schema::parser::parse_message_type(SCHEMA).map_err(error)?);
+        let properties = WriterProperties::builder()
+            .set_key_value_metadata(Some(vec![KeyValue::new(
+                "model".to_string(),
+                model.to_string(),
+            )]))
+            .build();
+        let file = std::fs::File::create(path).map_err(|e| Error::io(path, e))?;
+        let mut writer =
+            SerializedFileWriter::new(file, schema, Arc::new(properties)).map_err(error)?;
+        let vectors: Vec<&[f32]> = vectors.collect();
+        for (paths, vectors) in paths.chunks(ROW_GROUP).zip(vectors.chunks(ROW_GROUP)) {
+            let mut row_group = writer.next_row_group().map_err(error)?;
+            if let Some(mut column) = row_group.next_column().map_err(error)? {
+                let values: Vec<ByteArray> = paths.iter().map(|p| p.as_bytes().into()).collect();
+                column
+                    .typed::<ByteArrayType>()
+                    .write_batch(&values, None, None)
+                    .map_err(error)?;
+                column.close().map_err(error)?;
+            }
+            if let Some(mut column) = row_group.next_column().map_err(error)? {
+                let values: Vec<f32> = vectors.iter().flat_map(|v| v.iter().copied()).collect();
+                let definitions = vec![1; values.len()];
+                // each list starts a new row
+                let repetitions: Vec<i16> = vectors
+                    .iter()
+                    .flat_map(|v| (0..v.len()).map(|i| (i > 0) as i16))
+                    .collect();
+                column
+                    .typed::<FloatType>()
+                    .write_batch(&values, Some(&definitions), Some(&repetitions))
+                    .map_err(error)?;
+                column.close().map_err(error)?;
+            }
+            row_group.close().map_err(error)?;
+        }
+        writer.close().map_err(error)?;
+        Ok(())
+    }
+
+    pub fn read(path: &Path) -> Result<Embeddings> {
+        let error = |e: parquet::errors::ParquetError| invalid(path, e.to_string());
+        let file = std::fs::File::open(path).map_err(|e| Error::io(path, e))?;
+        let reader = SerializedFileReader::new(file).map_err(error)?;
+        let model = reader
+            .metadata()
+            .file_metadata()
+            .key_value_metadata()
+            .and_then(|pairs| pairs.iter().find(|pair| pair.key == "model"))
+            .and_then(|pair| pair.value.clone());
+        let mut embeddings = Embeddings {
+            model,
+            ..Default::default()
+        };
+        for row in reader.get_row_iter(None).map_err(error)? {
+            let row = row.map_err(error)?;
+            let (mut image, mut vector) = (None, None);
+            for (i, (name, _)) in row.get_column_iter().enumerate() {
+                match name.as_str() {
+                    "path" => image = Some(row.get_string(i).map_err(error)?.clone()),
+                    "embedding" => {
+                        let list = row.get_list(i).map_err(error)?;
+                        let values = list.elements().iter().map(|field| match field {
+                            Field::Float(x) => Ok(*x),
+                            Field::Double(x) => Ok(*x as f32),
+                            _ => Err(invalid(path, "embeddings are not floats")),
+                        });
+                        vector = Some(values.collect::<Result<Vec<f32>>>()?);
+                    }
+                    _ => {}
+                }
+            }
+            let (Some(image), Some(vector)) = (image, vector) else {
+                return Err(invalid(path, "no path and embedding columns"));
+            };
+            embeddings.paths.push(image);
+            embeddings.vectors.push(vector);
+        }
+        Ok(embeddings)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_export_import() {
+        let mut database = Database::default();
+        let path = database::absolute_key("a.jpg");
+        database.insert(path.clone(), vec![0.6, 0.8]);
+        let dir = std::env::temp_dir().join(format!("imgfind-export-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        for &format in <ExportFormat as clap::ValueEnum>::value_variants() {
+            if format == ExportFormat::Parquet && !cfg!(feature = "parquet") {
+                continue;
+            }
+            let name = clap::ValueEnum::to_possible_value(&format).unwrap();
+            let file = dir.join("embeddings").with_extension(name.get_name());
+            assert_eq!(ExportFormat::of(&file), Some(format));
+            export(&database, &file, format).unwrap();
+            let embeddings = import(&file, format).unwrap();
+            assert_eq!(embeddings.paths, [path.as_str()]);
+            assert_eq!(embeddings.vectors, [vec![0.6, 0.8]]);
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        let mut other = Database::default();
+        let embeddings = |model: &str, vector: Vec<f32>| Embeddings {
+            model: Some(model.to_string()),
+            paths: vec!["b.jpg".to_string()],
+            vectors: vec![vector],
+        };
+        let model = other.model.clone();
+        assert!(merge(&mut other, embeddings("other", vec![1.0; 512]), false).is_err());
+        assert!(merge(&mut other, embeddings(&model, vec![1.0; 3]), false).is_err());
+        assert_eq!(
+            merge(&mut other, embeddings(&model, vec![2.0; 512]), false).unwrap(),
+            (1, 0)
+        );
+        assert_eq!(
+            merge(&mut other, embeddings(&model, vec![2.0; 512]), false).unwrap(),
+            (0, 1)
+        );
+        let root = database::absolute_key(".");
+        assert_eq!(other.roots.len(), 1);
+        assert_eq!(other.roots[0].location, root);
+    }
+    #[cfg(unix)]
+    #[test]
+    fn test_exported_paths() {
+        use std::os::unix::ffi::OsStrExt;
+        let raw = Path::new(std::ffi::OsStr::from_bytes(b"/photos/caf\xe9\\x41.jpg"));
+        let key = database::path_key(raw).unwrap();
+        assert_eq!(exported_path(&key), r"/photos/caf\xe9\\x41.jpg");
+        assert_eq!(imported_key(&exported_path(&key)), key);
+        assert_eq!(imported_key(r"/photos/a\b\x4.jpg"), r"/photos/a\b\x4.jpg");
+    }
+    #[test]
+    fn test_npy_sizes() {
+        let path = Path::new("embeddings.npy");
+        let strings = npy::strings(&["a.jpg", "b.jpg"], &[2]);
+        assert_eq!(
+            npy::read_strings(path, &strings).unwrap(),
+            ["a.jpg", "b.jpg"]
+        );
+        assert!(npy::read_strings(path, &npy::array("<U0", &[2], &[])).is_err());
+        let huge = [usize::MAX / 2, 2];
+        assert!(npy::read_strings(path, &npy::array("<U1", &huge, &[])).is_err());
+        assert!(npy::read_floats(path, &npy::array("<f4", &huge, &[])).is_err());
+    }
+}
@@ -0,0 +1,184 @@
+//! Conditional and partial GET support for serving original files, and
+//! parsing of uploaded request bodies.
+use crate::minhttpd::HttpHeaders;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Formats a time as an HTTP date, e.g. `Sun, 06 Nov 1994 08:49:37 GMT`.
+pub fn http_date(time: SystemTime) -> String {
+    const DAYS: [&str; 7] = ["Thu", "Fri", "Sat", "Sun", "Mon", "Tue", "Wed"];
+    const MONTHS: [&str; 12] = [
+        "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+    ];
+    let secs = time.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs());
+    let (days, secs) = (secs / 86400, secs % 86400);
+    // days since the epoch to a civil date, from Howard Hinnant's `civil_from_days`
+    let z = days as i64 + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + (month <= 2) as i64;
+    format!(
+        "{}, {:02} {} {} {:02}:{:02}:{:02} GMT",
+        DAYS[(days % 7) as usize],
+        day,
+        MONTHS[month as usize - 1],
+        year,
+        secs / 3600,
+        secs / 60 % 60,
+        secs % 60
+    )
+}
+
+/// Validators of a file, derived from its modification time and length.
+pub struct Validators {
+    pub etag: String,
+    pub last_modified: String,
+}
+
+impl Validators {
+    pub fn new(metadata: &std::fs::Metadata) -> Self {
+        let modified = metadata.modified().unwrap_or(UNIX_EPOCH);
+        let nanos = modified
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_nanos());
+        Self {
+            etag: format!("\"{:x}-{:x}\"", nanos, metadata.len()),
+            last_modified: http_date(modified),
+        }
+    }
+
+    /// Whether the client's cached copy is still current, so 304 can be sent.
+    pub fn not_modified(&self, headers: &HttpHeaders) -> bool {
+        match headers.get("if-none-match") {
+            Some(tags) => tags
+                .split(',')
+                .any(|tag| tag.trim() == "*" || tag.trim().trim_start_matches("W/") == self.etag),
+            None => headers.get("if-modified-since") == Some(&self.last_modified),
+        }
+    }
+
+    /// Whether a `Range` request may be honoured, checking `If-Range`.
+    pub fn range_applies(&self, headers: &HttpHeaders) -> bool {
+        match headers.get("if-range") {
+            Some(validator) => *validator == self.etag || *validator == self.last_modified,
+            None => true,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Range {
+    /// No usable range, send the whole file.
+    Full,
+    /// An inclusive byte range within the file.
+    Partial(u64, u64),
+    Unsatisfiable,
+}
+
+/// Parses a `Range` header against a file of `len` bytes. Only a single byte
+/// range is supported; anything else is answered with the full file.
+pub fn parse_range(header: Option<&String>, len: u64) -> Range {
+    let Some(spec) = header.and_then(|h| h.trim().strip_prefix("bytes=")) else {
+        return Range::Full;
+    };
+    if spec.contains(',') {
+        return Range::Full;
+    }
+    let Some((start, end)) = spec.trim().split_once('-') else {
+        return Range::Full;
+    };
+    let (start, end) = match (start.parse::<u64>(), end.parse::<u64>()) {
+        (Ok(start), Ok(end)) if start <= end => (start, end.min(len.saturating_sub(1))),
+        (Ok(start), Err(_)) if end.is_empty() => (start, len.saturating_sub(1)),
+        (Err(_), Ok(suffix)) if start.is_empty() && suffix > 0 => {
+            (len.saturating_sub(suffix), len.saturating_sub(1))
+        }
+        _ => return Range::Full,
+    };
+    if start >= len {
+        Range::Unsatisfiable
+    } else {
+        Range::Partial(start, end)
+    }
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack
+        .windows(needle.len())
+        .position(|window| window == needle)
+}
+
+/// Returns the content of the first file in a `multipart/form-data` body.
+pub fn multipart_file<'a>(content_type: &str, body: &'a [u8]) -> Option<&'a [u8]> {
+    let boundary = content_type
+        .split(';')
+        .find_map(|param| param.trim().strip_prefix("boundary="))?
+        .trim_matches('"');
+    let delimiter = format!("--{}", boundary);
+    // skip the preamble before the first delimiter
+    let mut rest = &body[find(body, delimiter.as_bytes())? + delimiter.len()..];
+    loop {
+        // the last delimiter is followed by "--"
+        let part = rest.strip_prefix(b"\r\n")?;
+        let end = find(part, delimiter.as_bytes())?;
+        let (part, next) = (&part[..end], &part[end + delimiter.len()..]);
+        let header_end = find(part, b"\r\n\r\n")?;
+        let headers = String::from_utf8_lossy(&part[..header_end]);
+        let is_file = headers.lines().any(|line| {
+            line.to_ascii_lowercase()
+                .starts_with("content-disposition:")
+                && line.contains("filename=")
+        });
+        if is_file {
+            let content = &part[header_end + 4..];
+            return Some(content.strip_suffix(b"\r\n").unwrap_or(content));
+        }
+        rest = next;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn test_http_date() {
+        let time = UNIX_EPOCH + Duration::from_secs(784111777);
+        assert_eq!(http_date(time), "Sun, 06 Nov 1994 08:49:37 GMT");
+        assert_eq!(http_date(UNIX_EPOCH), "Thu, 01 Jan 1970 00:00:00 GMT");
+    }
+
+    #[test]
+    fn test_parse_range() {
+        let range = |h: &str| parse_range(Some(&h.to_string()), 1000);
+        assert_eq!(range("bytes=0-99"), Range::Partial(0, 99));
+        assert_eq!(range("bytes=900-"), Range::Partial(900, 999));
+        assert_eq!(range("bytes=-100"), Range::Partial(900, 999));
+        assert_eq!(range("bytes=500-5000"), Range::Partial(500, 999));
+        assert_eq!(range("bytes=1000-"), Range::Unsatisfiable);
+        assert_eq!(range("bytes=0-1,5-6"), Range::Full);
+        assert_eq!(parse_range(None, 1000), Range::Full);
+    }
+
+    #[test]
+    fn test_multipart_file() {
+        let body =
+            b"preamble\r\n--xyz\r\nContent-Disposition: form-data; name=\"limit\"\r\n\r\n10\r\n\
+--xyz\r\nContent-Disposition: form-data; name=\"image\"; filename=\"a.png\"\r\n\
+Content-Type: image/png\r\n\r\n\x89PNG\r\n\x00\r\n--xyz--\r\n";
+        let content_type = "multipart/form-data; boundary=\"xyz\"";
+        assert_eq!(
+            multipart_file(content_type, body),
+            Some(&b"\x89PNG\r\n\x00"[..])
+        );
+        assert_eq!(
+            multipart_file("multipart/form-data; boundary=abc", body),
+            None
+        );
+    }
+}
@@ -0,0 +1,224 @@
+//! Long running work started over HTTP, such as indexing a directory.
+use serde::Serialize;
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum JobKind {
+    Add,
+    Check,
+    Dupes,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum JobState {
+    Running,
+    Done,
+    Failed,
+}
+
+pub struct Job {
+    pub id: usize,
+    pub kind: JobKind,
+    pub path: Option<String>,
+    started: Instant,
+    pub total: AtomicUsize,
+    pub processed: AtomicUsize,
+    pub failed: AtomicUsize,
+    /// The summary or error once the job has ended, and how long it took.
+    outcome: Mutex<Option<(Result<String, String>, Duration)>>,
+    /// What a job that computes something, rather than changing the
+    /// database, leaves behind for `/api/jobs?id=` to report.
+    result: Mutex<Option<serde_json::Value>>,
+}
+
+/// A snapshot of a job as reported by `/api/jobs`.
+#[derive(Debug, Serialize)]
+pub struct JobStatus {
+    pub id: usize,
+    pub kind: JobKind,
+    pub path: Option<String>,
+    pub state: JobState,
+    pub processed: usize,
+    pub total: usize,
+    pub failed: usize,
+    pub elapsed_ms: u64,
+    /// Estimated time left, once there is some progress to go by.
+    pub eta_ms: Option<u64>,
+    pub message: Option<String>,
+}
+
+/// Extrapolates the time left from the rate so far.
+fn eta(elapsed: Duration, processed: usize, total: usize) -> Option<Duration> {
+    if processed == 0 {
+        return None;
+    }
+    let left = total.saturating_sub(processed) as u32;
+    Some(elapsed / processed as u32 * left)
+}
+
+impl Job {
+    fn finish(&self, outcome: Result<String, String>) {
+        *self.outcome.lock().unwrap() = Some((outcome, self.started.elapsed()));
+    }
+
+    pub fn set_result(&self, result: serde_json::Value) {
+        *self.result.lock().unwrap() = Some(result);
+    }
+
+    pub fn result(&self) -> Option<serde_json::Value> {
+        self.result.lock().unwrap().clone()
+    }
+
+    pub fn is_running(&self) -> bool {
+        self.outcome.lock().unwrap().is_none()
+    }
+
+    pub fn status(&self) -> JobStatus {
+        let processed = self.processed.load(Ordering::Relaxed);
+        let total = self.total.load(Ordering::Relaxed);
+        let (state, elapsed, eta, message) = match &*self.outcome.lock().unwrap() {
+            None => {
+                let elapsed = self.started.elapsed();
+                let eta = eta(elapsed, processed, total);
+                (JobState::Running, elapsed, eta, None)
+            }
+            Some((outcome, elapsed)) => {
+                let (state, message) = match outcome {
+                    Ok(summary) => (JobState::Done, summary),
+                    Err(error) => (JobState::Failed, error),
+                };
+                (state, *elapsed, None, Some(message.clone()))
+            }
+        };
+        JobStatus {
+            id: self.id,
+            kind: self.kind,
+            path: self.path.clone(),
+            state,
+            processed,
+            total,
+            failed: self.failed.load(Ordering::Relaxed),
+            elapsed_ms: elapsed.as_millis() as u64,
+            eta_ms: eta.map(|eta| eta.as_millis() as u64),
+            message,
+        }
+    }
+}
+
+/// Finished jobs that are kept to be reported, oldest first. Their results
+/// can be large, such as every group of duplicates, so older ones are dropped.
+const KEPT_JOBS: usize = 16;
+
+#[derive(Default)]
+pub struct Jobs {
+    jobs: Mutex<Vec<Arc<Job>>>,
+    next_id: AtomicUsize,
+}
+
+impl Jobs {
+    /// Runs `run` on a new thread, unless another job is still running: jobs
+    /// write to the database or scan all of it, so only one runs at a time.
+    /// `run` returns a summary of what it did.
+    pub fn start<F>(&self, kind: JobKind, path: Option<String>, run: F) -> Option<Arc<Job>>
+    where
+        F: FnOnce(&Job) -> Result<String, String> + Send + 'static,
+    {
+        let mut jobs = self.jobs.lock().unwrap();
+        if jobs.iter().any(|job| job.is_running()) {
+            return None;
+        }
+        // only one job runs at a time, so none of the oldest is still running
+        let excess = (jobs.len() + 1).saturating_sub(KEPT_JOBS);
+        jobs.drain(..excess);
+        let job = Arc::new(Job {
+            id: self.next_id.fetch_add(1, Ordering::Relaxed),
+            kind,
+            path,
+            started: Instant::now(),
+            total: AtomicUsize::new(0),
+            processed: AtomicUsize::new(0),
+            failed: AtomicUsize::new(0),
+            outcome: Mutex::new(None),
+            result: Mutex::new(None),
+        });
+        jobs.push(job.clone());
+        let running = job.clone();
+        std::thread::spawn(move || {
+            // a panicking job must not be reported as running forever
+            let outcome = catch_unwind(AssertUnwindSafe(|| run(&running)))
+                .unwrap_or_else(|_| Err("job panicked".to_string()));
+            running.finish(outcome);
+        });
+        Some(job)
+    }
+
+    pub fn get(&self, id: usize) -> Option<Arc<Job>> {
+        self.jobs
+            .lock()
+            .unwrap()
+            .iter()
+            .find(|job| job.id == id)
+            .cloned()
+    }
+
+    pub fn list(&self) -> Vec<Arc<Job>> {
+        self.jobs.lock().unwrap().clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_jobs() {
+        assert_eq!(eta(Duration::from_secs(10), 0, 100), None);
+        assert_eq!(
+            eta(Duration::from_secs(10), 25, 100),
+            Some(Duration::from_secs(30))
+        );
+
+        let jobs = Jobs::default();
+        let (tx, rx) = std::sync::mpsc::channel::<()>();
+        let job = jobs
+            .start(JobKind::Check, None, move |job| {
+                job.total.store(2, Ordering::Relaxed);
+                rx.recv().unwrap();
+                job.set_result(serde_json::json!([1, 2]));
+                Ok("done".to_string())
+            })
+            .unwrap();
+        assert_eq!(job.status().state, JobState::Running);
+        assert!(jobs
+            .start(JobKind::Check, None, |_| Ok(String::new()))
+            .is_none());
+        tx.send(()).unwrap();
+        while job.is_running() {
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        let status = jobs.get(0).unwrap().status();
+        assert_eq!(status.state, JobState::Done);
+        assert_eq!(status.message.as_deref(), Some("done"));
+        assert_eq!(
+            jobs.get(0).unwrap().result(),
+            Some(serde_json::json!([1, 2]))
+        );
+
+        for _ in 0..KEPT_JOBS {
+            let job = jobs
+                .start(JobKind::Check, None, |_| Ok(String::new()))
+                .unwrap();
+            while job.is_running() {
+                std::thread::sleep(Duration::from_millis(1));
+            }
+        }
+        assert!(jobs.get(0).is_none());
+        assert_eq!(jobs.list().len(), KEPT_JOBS);
+        assert_eq!(jobs.get(KEPT_JOBS).unwrap().id, KEPT_JOBS);
+    }
+}
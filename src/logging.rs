@@ -0,0 +1,151 @@
+//! Logging to stderr, with progress bars that make way for log lines.
+use indicatif::{MultiProgress, ProgressBar, ProgressDrawTarget, ProgressStyle};
+use log::kv::{self, Key, Value, VisitSource};
+use log::{LevelFilter, Log, Metadata, Record};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::OnceLock;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, clap::ValueEnum)]
+pub enum LogFormat {
+    #[default]
+    Text,
+    Json,
+}
+
+static PROGRESS: OnceLock<MultiProgress> = OnceLock::new();
+/// Progress bars are only drawn for people: not with JSON logs, and not
+/// when only warnings are wanted.
+static SHOW_PROGRESS: AtomicBool = AtomicBool::new(true);
+
+fn progress() -> &'static MultiProgress {
+    PROGRESS.get_or_init(|| MultiProgress::with_draw_target(ProgressDrawTarget::stderr()))
+}
+
+struct Logger {
+    level: LevelFilter,
+    format: LogFormat,
+}
+
+struct Fields(Vec<(String, serde_json::Value)>);
+
+impl<'kvs> VisitSource<'kvs> for Fields {
+    fn visit_pair(&mut self, key: Key<'kvs>, value: Value<'kvs>) -> Result<(), kv::Error> {
+        let value = if let Some(n) = value.to_u64() {
+            n.into()
+        } else if let Some(n) = value.to_i64() {
+            n.into()
+        } else if let Some(b) = value.to_bool() {
+            b.into()
+        } else {
+            value.to_string().into()
+        };
+        self.0.push((key.to_string(), value));
+        Ok(())
+    }
+}
+
+fn format_record(format: LogFormat, record: &Record) -> String {
+    let mut fields = Fields(Vec::new());
+    let _ = record.key_values().visit(&mut fields);
+    let level = record.level().as_str().to_lowercase();
+    match format {
+        LogFormat::Text => {
+            let mut line = format!("{}: {}", level, record.args());
+            for (key, value) in fields.0 {
+                match value {
+                    serde_json::Value::String(s) if s.contains(char::is_whitespace) => {
+                        line += &format!(" {}={:?}", key, s)
+                    }
+                    serde_json::Value::String(s) => line += &format!(" {}={}", key, s),
+                    value => line += &format!(" {}={}", key, value),
+                }
+            }
+            line
+        }
+        LogFormat::Json => {
+            let time = std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .map_or(0.0, |d| d.as_secs_f64());
+            let mut object = serde_json::Map::new();
+            object.insert("time".into(), time.into());
+            object.insert("level".into(), level.into());
+            object.insert("target".into(), record.target().into());
+            object.insert("message".into(), record.args().to_string().into());
+            object.extend(fields.0);
+            serde_json::Value::Object(object).to_string()
+        }
+    }
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        // other crates are only heard from for warnings, unless tracing
+        let level = if metadata.target().starts_with(env!("CARGO_CRATE_NAME"))
+            || self.level == LevelFilter::Trace
+        {
+            self.level
+        } else {
+            self.level.min(LevelFilter::Warn)
+        };
+        metadata.level() <= level
+    }
+
+    fn log(&self, record: &Record) {
+        if self.enabled(record.metadata()) {
+            let line = format_record(self.format, record);
+            progress().suspend(|| eprintln!("{}", line));
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+pub fn init(level: LevelFilter, format: LogFormat) {
+    SHOW_PROGRESS.store(
+        format == LogFormat::Text && level >= LevelFilter::Info,
+        Ordering::Relaxed,
+    );
+    log::set_max_level(level);
+    let _ = log::set_boxed_logger(Box::new(Logger { level, format }));
+}
+
+/// A bar counting up to `len`, with throughput and time left.
+pub fn progress_bar(len: u64, message: &str) -> ProgressBar {
+    if !SHOW_PROGRESS.load(Ordering::Relaxed) {
+        return ProgressBar::hidden();
+    }
+    let style =
+        ProgressStyle::with_template("{msg} [{wide_bar}] {pos}/{len} {per_sec:.dim} eta {eta}")
+            .unwrap()
+            .progress_chars("=> ");
+    progress().add(
+        ProgressBar::new(len)
+            .with_style(style)
+            .with_message(message.to_string()),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_format_record() {
+        let fields: [(&str, Value); 2] = [("path", "a b.jpg".into()), ("count", 3u64.into())];
+        let record = Record::builder()
+            .args(format_args!("failed"))
+            .level(log::Level::Warn)
+            .target("imgfind")
+            .key_values(&fields)
+            .build();
+        assert_eq!(
+            format_record(LogFormat::Text, &record),
+            "warn: failed path=\"a b.jpg\" count=3"
+        );
+        let json: serde_json::Value =
+            serde_json::from_str(&format_record(LogFormat::Json, &record)).unwrap();
+        assert_eq!(json["level"], "warn");
+        assert_eq!(json["path"], "a b.jpg");
+        assert_eq!(json["count"], 3);
+    }
+}
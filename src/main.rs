@@ -1,20 +1,47 @@
+mod api;
+mod auth;
+mod cli;
+mod cluster;
+mod config;
+mod database;
+mod dupes;
+mod error;
+mod export;
+mod http;
+mod jobs;
+mod logging;
+mod minhttpd;
 mod model;
+mod output;
+mod report;
+mod scan;
+mod tag;
+mod thumbnail;
+#[cfg(feature = "tls")]
+mod tls;
+mod userdata;
+mod watch;
+use crate::minhttpd::{HttpBody, HttpHeaders, HttpParams, HttpResponse, MinHttpd};
+use api::{ApiError, ApiResult};
 use candle_core::Module;
 use candle_core::{DType, Device, Tensor};
 use candle_nn::VarBuilder;
+use clap::{CommandFactory, Parser};
+use cli::Command;
+use database::{load_database, save_database, Database, Embedding};
+use error::Error;
 use std::collections::BTreeMap;
-use std::net::{Ipv4Addr, SocketAddrV4};
-use std::sync::Arc;
-use tokenizers::tokenizer;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, OnceLock, RwLock, RwLockReadGuard};
 use tokenizers::tokenizer::Tokenizer;
-use xjbutil::minhttpd::{HttpBody, HttpHeaders, HttpParams, HttpResponse, HttpUri, MinHttpd};
+use userdata::{UserData, UserStore};
 
 #[cfg(feature = "heif")]
-fn load_heif(p: &str) -> candle_core::Result<image::DynamicImage> {
+fn decode_heif(ctx: libheif_rs::HeifContext) -> candle_core::Result<image::DynamicImage> {
     use candle_core::Error;
-    use libheif_rs::{ColorSpace, HeifContext, LibHeif, RgbChroma};
+    use libheif_rs::{ColorSpace, LibHeif, RgbChroma};
     let lib_heif = LibHeif::new();
-    let ctx = HeifContext::read_from_file(p).map_err(Error::wrap)?;
     let handle = ctx.primary_image_handle().map_err(Error::wrap)?;
     // Decode the image
     let image = lib_heif
@@ -34,28 +61,75 @@ fn load_heif(p: &str) -> candle_core::Result<image::DynamicImage> {
     ))
 }
 
+#[cfg(feature = "heif")]
+fn load_heif(p: &str) -> candle_core::Result<image::DynamicImage> {
+    use libheif_rs::HeifContext;
+    let path = database::key_path(p);
+    let bytes;
+    // libheif only opens files by a UTF-8 name, others are read into memory
+    let ctx = match path.to_str() {
+        Some(name) => HeifContext::read_from_file(name),
+        None => {
+            bytes = std::fs::read(&path)?;
+            HeifContext::read_from_bytes(&bytes)
+        }
+    }
+    .map_err(candle_core::Error::wrap)?;
+    decode_heif(ctx)
+}
+
 fn get_extension<P: AsRef<std::path::Path>>(p: P) -> String {
     p.as_ref()
         .extension()
         .unwrap_or_default()
-        .to_str()
-        .unwrap()
+        .to_string_lossy()
         .to_lowercase()
 }
 
-fn load_image224(p: &str) -> candle_core::Result<Tensor> {
+fn load_image(p: &str) -> error::Result<image::DynamicImage> {
     let extension = get_extension(p);
-    let img = if extension == "heic" || extension == "heif" {
+    if extension == "heic" || extension == "heif" {
         #[cfg(not(feature = "heif"))]
-        return Err(candle_core::Error::Msg("heif support not enabled".into()));
+        return Err(Error::Unsupported {
+            path: p.to_string(),
+            message: "heif support not enabled".to_string(),
+        });
         #[cfg(feature = "heif")]
-        load_heif(p)?
+        load_heif(p).map_err(|e| Error::Decode {
+            path: p.to_string(),
+            source: Box::new(e),
+        })
     } else {
-        image::io::Reader::open(p)?
+        image::io::Reader::open(database::key_path(p))
+            .map_err(|e| Error::io(p, e))?
             .decode()
-            .map_err(candle_core::Error::wrap)?
+            .map_err(|e| Error::image(p, e))
+    }
+}
+
+/// Whether `bytes` start like an HEIF/HEIC file, judged by the `ftyp` box.
+fn is_heif(bytes: &[u8]) -> bool {
+    const BRANDS: [&[u8]; 6] = [b"heic", b"heix", b"hevc", b"hevx", b"mif1", b"msf1"];
+    bytes.len() >= 12 && &bytes[4..8] == b"ftyp" && BRANDS.contains(&&bytes[8..12])
+}
+
+/// Decodes an image held in memory, such as an upload, guessing its format
+/// from the content.
+fn decode_image(bytes: &[u8]) -> candle_core::Result<image::DynamicImage> {
+    if is_heif(bytes) {
+        #[cfg(not(feature = "heif"))]
+        return Err(candle_core::Error::Msg("heif support not enabled".into()));
+        #[cfg(feature = "heif")]
+        decode_heif(
+            libheif_rs::HeifContext::read_from_bytes(bytes).map_err(candle_core::Error::wrap)?,
+        )
+    } else {
+        image::load_from_memory(bytes).map_err(candle_core::Error::wrap)
     }
-    .resize_to_fill(224, 224, image::imageops::FilterType::Triangle);
+}
+
+fn image224(img: &image::DynamicImage) -> candle_core::Result<Tensor> {
+    let img = img.resize_to_fill(224, 224, image::imageops::FilterType::Triangle);
     let img = img.to_rgb8();
     let data = img.into_raw();
     let data = Tensor::from_vec(data, (224, 224, 3), &Device::Cpu)?.permute((2, 0, 1))?;
@@ -66,116 +140,622 @@ fn load_image224(p: &str) -> candle_core::Result<Tensor> {
         .broadcast_div(&std)
 }
 
-type Embedding = Vec<f32>;
-type Database = BTreeMap<String, Embedding>;
+/// Default cosine similarity above which two images count as near-duplicates.
+const DUPES_THRESHOLD: f32 = 0.95;
+/// Default number of topics created by the `cluster` command.
+const CLUSTER_COUNT: usize = 20;
+/// Default probability above which the `tag` command assigns a tag.
+const TAG_THRESHOLD: f32 = 0.3;
+/// Largest request body `serve` accepts, which bounds image uploads.
+const MAX_UPLOAD_SIZE: usize = 32 * 1024 * 1024;
+/// Files expected in the model directory.
+const MODEL_FILE: &str = "model.safetensors";
+const TOKENIZER_FILE: &str = "tokenizer.json";
 
-fn load_database() -> Database {
-    match std::fs::File::open("database.bin") {
-        Ok(file) => rmp_serde::from_read(file).expect("failed to read database.bin"),
-        Err(_) => BTreeMap::new(),
-    }
+fn load_vision_model(
+    model_dir: &std::path::Path,
+) -> candle_core::Result<model::ClipVisionTransformer> {
+    let weights = unsafe { candle_core::safetensors::MmapedFile::new(model_dir.join(MODEL_FILE))? };
+    let weights = weights.deserialize()?;
+    let vb = VarBuilder::from_safetensors(vec![weights], DType::F32, &Device::Cpu);
+    model::ClipVisionTransformer::new(vb, &model::Config::vision())
 }
 
-fn save_database(database: &Database) {
-    let mut file = std::fs::File::create("database.bin").expect("failed to create database.bin");
-    rmp_serde::encode::write(&mut file, database).expect("failed to write database.bin");
+/// Reads CLIP's learned temperature, used to turn similarities into logits.
+fn load_logit_scale(model_dir: &std::path::Path) -> candle_core::Result<f32> {
+    let weights = unsafe { candle_core::safetensors::MmapedFile::new(model_dir.join(MODEL_FILE))? };
+    let weights = weights.deserialize()?;
+    let vb = VarBuilder::from_safetensors(vec![weights], DType::F32, &Device::Cpu);
+    vb.get((), "logit_scale")?.to_scalar::<f32>().map(f32::exp)
 }
 
-fn add_image_feature(
-    database: &mut Database,
+fn load_text_model(
+    model_dir: &std::path::Path,
+    tokenizer: &std::path::Path,
+) -> error::Result<(model::ClipTextTransformer, Tokenizer)> {
+    let weights = unsafe { candle_core::safetensors::MmapedFile::new(model_dir.join(MODEL_FILE))? };
+    let weights = weights.deserialize()?;
+    let vb = VarBuilder::from_safetensors(vec![weights], DType::F32, &Device::Cpu);
+    let model = model::ClipTextTransformer::new(vb, &model::Config::clip())?;
+    let tokenizer = Tokenizer::from_file(tokenizer).map_err(Error::Model)?;
+    Ok((model, tokenizer))
+}
+
+/// Embeds an image file, and when given a thumbnail cache also stores the
+/// thumbnails the web UI asks for there while the decoded image is at hand.
+fn image_feature(
     model: &model::ClipVisionTransformer,
     path: &str,
-) -> candle_core::Result<()> {
-    let img = load_image224(path)?.unsqueeze(0)?;
+    thumbnails: Option<&std::path::Path>,
+) -> error::Result<Embedding> {
+    let img = load_image(path)?;
+    if let Some(dir) = thumbnails {
+        let stored = thumbnail::content_hash(path)
+            .map_err(|e| e.into())
+            .and_then(|hash| thumbnail::store_all(dir, hash, &img));
+        if let Err(e) = stored {
+            log::warn!(path; "failed to create thumbnail: {}", e);
+        }
+    }
+    Ok(encode_image(model, &img)?)
+}
+
+/// Logs an image that could not be indexed.
+fn log_failure(path: &str, error: &Error) {
+    log::warn!(path, kind:% = error.kind(); "failed to process image: {}", error);
+}
+
+/// Encodes an image into a normalized CLIP embedding.
+fn encode_image(
+    model: &model::ClipVisionTransformer,
+    img: &image::DynamicImage,
+) -> candle_core::Result<Embedding> {
+    let img = image224(img)?.unsqueeze(0)?;
     let output: Vec<f32> = model.forward(&img)?.squeeze(0)?.to_vec1()?;
-    let output = normalize(&output);
-    database.insert(path.to_string(), output);
-    Ok(())
+    Ok(normalize(&output))
 }
 
-fn get_images(path: &str) -> Vec<String> {
-    let mut result = Vec::new();
-    fn recurse(path: &str, result: &mut Vec<String>) {
-        for entry in std::fs::read_dir(path).expect("failed to read dir") {
-            let entry = entry.expect("failed to read entry");
-            let path = entry.path();
-            if path.is_dir() {
-                let path = path.to_string_lossy();
-                if path.starts_with('.') {
-                    continue;
-                }
-                recurse(&path, result);
-            } else {
-                let extension = get_extension(&path);
-                let path = path.to_string_lossy();
-                match extension.as_str() {
-                    "jpg" | "jpeg" | "png" | "heic" | "heif" => result.push(path.to_string()),
-                    _ => {}
-                }
-            }
-        }
-    }
-    recurse(path, &mut result);
-    result
+/// Whether the file has one of the extensions that are indexed.
+fn is_image(path: &std::path::Path) -> bool {
+    matches!(
+        get_extension(path).as_str(),
+        "jpg" | "jpeg" | "png" | "heic" | "heif"
+    )
 }
 
-fn find_image<'a>(
-    database: &'a Database,
+/// Starts finding the images below `paths` that `filter` lets through.
+/// Only an unreadable path is an error; directories below them that cannot
+/// be read are logged and skipped.
+fn scan_images(paths: &[String], filter: &scan::Filter) -> error::Result<scan::Scan> {
+    let walks = paths
+        .iter()
+        .map(|path| {
+            scan::Walk::new(path.as_ref(), filter, &scan::Ignores::default())
+                .map_err(|e| Error::io(path, e))
+        })
+        .collect::<error::Result<_>>()?;
+    Ok(scan::Scan::spawn(walks))
+}
+
+/// Makes `paths` absolute and registers them as roots of `database`, so
+/// the images below them are stored relative to them.
+fn add_roots(database: &mut Database, paths: &[String]) -> error::Result<Vec<String>> {
+    paths
+        .iter()
+        .map(|path| {
+            let absolute =
+                std::path::absolute(database::key_path(path)).map_err(|e| Error::io(path, e))?;
+            let key = database::path_key(&absolute).unwrap_or_else(|| path.clone());
+            database.add_root(&key);
+            Ok(key)
+        })
+        .collect()
+}
+
+/// Lists the images below `path`, a directory inside a walk that has
+/// already collected the ignore files above it.
+fn get_images_below(
+    path: &str,
+    filter: &scan::Filter,
+    ignores: &scan::Ignores,
+) -> error::Result<Vec<String>> {
+    let walk = scan::Walk::new(&database::key_path(path), filter, ignores)
+        .map_err(|e| Error::io(path, e))?;
+    Ok(walk.filter_map(|path| database::path_key(&path)).collect())
+}
+
+/// Encodes `text` into a normalized CLIP embedding.
+fn encode_text(
     model: &model::ClipTextTransformer,
     tokenizer: &Tokenizer,
     text: &str,
-) -> tokenizer::Result<Vec<(&'a String, f32)>> {
+) -> error::Result<Embedding> {
     let mut text_ids = [0u32; 77];
-    let encoding = tokenizer.encode(text, true)?;
+    let encoding = tokenizer
+        .encode(text, true)
+        .map_err(|e| Error::Query(format!("failed to tokenize '{}': {}", text, e)))?;
     let encoding_len = encoding.get_ids().len().min(77);
     text_ids[..encoding_len].copy_from_slice(&encoding.get_ids()[..encoding_len]);
     let feature: Vec<f32> = model
         .forward(&Tensor::from_vec(text_ids.to_vec(), (1, 77), &Device::Cpu)?)?
         .squeeze(0)?
         .to_vec1()?;
-    let feature = normalize(&feature);
-    let mut result = Vec::new();
-    for (path, embedding) in database.iter() {
-        let similarity = dot_product(embedding, &feature);
-        result.push((path, similarity));
-    }
-    result.sort_by(|(_, a), (_, b)| b.partial_cmp(a).unwrap());
-    Ok(result)
+    Ok(normalize(&feature))
+}
+
+fn find_image<'a>(
+    databases: &[(&'a str, &'a Database)],
+    model: &model::ClipTextTransformer,
+    tokenizer: &Tokenizer,
+    text: &str,
+) -> error::Result<Vec<Hit<'a>>> {
+    let feature = encode_text(model, tokenizer, text)?;
+    Ok(rank(databases, &feature))
+}
+
+/// A ranked image and the collection it was found in.
+#[derive(Debug, Clone, Copy)]
+struct Hit<'a> {
+    collection: &'a str,
+    database: &'a Database,
+    path: &'a String,
+    score: f32,
+}
+
+/// Scores every image of the named databases against `feature` and merges
+/// them into one list, best match first.
+fn rank<'a>(databases: &[(&'a str, &'a Database)], feature: &[f32]) -> Vec<Hit<'a>> {
+    let mut result: Vec<Hit> = databases
+        .iter()
+        .flat_map(|&(collection, database)| {
+            score_all(database, feature)
+                .into_iter()
+                .map(move |(path, score)| Hit {
+                    collection,
+                    database,
+                    path,
+                    score,
+                })
+        })
+        .collect();
+    sort_hits(&mut result);
+    result
+}
+
+/// Sorts hits best first. Scores that are not finite come from broken
+/// embeddings and are dropped, as NaN would otherwise be ranked first.
+fn sort_hits(hits: &mut Vec<Hit>) {
+    hits.retain(|hit| hit.score.is_finite());
+    hits.sort_by(|a, b| b.score.total_cmp(&a.score));
+}
+
+/// Threads to split work over, from `--threads` or one per core.
+static THREADS: OnceLock<usize> = OnceLock::new();
+
+/// How many threads scoring, duplicate search and clustering are split over.
+fn thread_count() -> usize {
+    *THREADS.get_or_init(|| std::thread::available_parallelism().map_or(1, |n| n.get()))
+}
+
+/// Scores every embedding in the database against `feature`, splitting the
+/// work into blocks that are processed on [`thread_count`] threads.
+fn score_all<'a>(database: &'a Database, feature: &[f32]) -> Vec<(&'a String, f32)> {
+    // below this many entries per thread, spawning costs more than it saves
+    const MIN_BLOCK: usize = 4096;
+    let entries: Vec<_> = database.embeddings.iter().collect();
+    let block = entries.len().div_ceil(thread_count()).max(MIN_BLOCK);
+    std::thread::scope(|s| {
+        let handles: Vec<_> = entries
+            .chunks(block)
+            .map(|chunk| {
+                s.spawn(move || {
+                    chunk
+                        .iter()
+                        .map(|&(path, embedding)| (path, dot_product(embedding, feature)))
+                        .collect::<Vec<_>>()
+                })
+            })
+            .collect();
+        handles
+            .into_iter()
+            .flat_map(|h| h.join().expect("scoring thread panicked"))
+            .collect()
+    })
 }
 
-fn command_add_image(database: &mut Database, path: &str, model: &model::ClipVisionTransformer) {
-    let images = get_images(path);
-    let len = images.len();
+/// Indexes the images not in the database yet as they are found,
+/// returning those that failed.
+fn command_add_images(
+    database: &mut Database,
+    mut images: scan::Scan,
+    model: &model::ClipVisionTransformer,
+    thumbnails: bool,
+) -> Vec<report::Failure> {
+    let started = std::time::Instant::now();
+    let thumbnails = thumbnails.then(|| thumbnail::cache_dir(database.path()));
+    let bar = logging::progress_bar(images.found() as u64, "indexing");
+    let (mut added, mut skipped) = (0, 0);
+    let mut failures = Vec::new();
     let mut count = 0;
-    for (i, image) in images.iter().enumerate() {
-        if database.contains_key(image) {
-            println!("skipping {}/{} {}", i + 1, len, image);
+    while let Some(image) = images.next() {
+        let image = &image;
+        bar.set_length(images.found() as u64);
+        bar.inc(1);
+        if database.embeddings.contains_key(image) {
+            log::trace!(path = image.as_str(); "skipping indexed image");
+            skipped += 1;
             continue;
         }
-        println!("processing {}/{} {}", i + 1, len, image);
-        if let Err(e) = add_image_feature(database, model, image) {
-            println!("failed to process {}: {}", image, e);
+        log::debug!(path = image.as_str(); "processing image");
+        match image_feature(model, image, thumbnails.as_deref()) {
+            Ok(feature) => {
+                database.insert(image.clone(), feature);
+                added += 1;
+            }
+            Err(e) => {
+                log_failure(image, &e);
+                failures.push(report::Failure::new(image, &e));
+            }
         }
         count += 1;
         // save database every 50 images
         if count == 50 {
-            println!("saving database");
-            save_database(database);
+            log::debug!("saving database");
+            if let Err(e) = save_database(database) {
+                log::error!("failed to save database: {}", e);
+            }
             count = 0;
         }
     }
+    bar.finish_and_clear();
+    let elapsed = started.elapsed().as_secs_f64();
+    log::info!(
+        added,
+        skipped,
+        failed = failures.len(),
+        elapsed:% = format!("{:.1}s", elapsed),
+        rate:% = format!("{:.1}/s", (added + failures.len()) as f64 / elapsed.max(1e-3));
+        "indexing finished"
+    );
+    failures
+}
+
+/// Indexes a directory for `/api/index/add`. Images are embedded without
+/// holding the lock, so searches keep running and see every new image as
+/// soon as it is inserted.
+fn index_images(
+    database: &RwLock<Database>,
+    model: &model::ClipVisionTransformer,
+    path: &str,
+    filter: &scan::Filter,
+    thumbnails: bool,
+    job: &jobs::Job,
+) -> Result<String, String> {
+    let paths = add_roots(&mut database.write().unwrap(), &[path.to_string()])
+        .map_err(|e| e.to_string())?;
+    let mut images = scan_images(&paths, filter).map_err(|e| e.to_string())?;
+    let thumbnails = thumbnails.then(|| thumbnail::cache_dir(database.read().unwrap().path()));
+    let mut added = 0;
+    while let Some(image) = images.next() {
+        job.total.store(images.found(), Ordering::Relaxed);
+        if !database.read().unwrap().embeddings.contains_key(&image) {
+            match image_feature(model, &image, thumbnails.as_deref()) {
+                Ok(feature) => {
+                    database.write().unwrap().insert(image, feature);
+                    added += 1;
+                    // save database every 50 images
+                    if added % 50 == 0 {
+                        if let Err(e) = save_database(&database.read().unwrap()) {
+                            log::error!("failed to save database: {}", e);
+                        }
+                    }
+                }
+                Err(e) => {
+                    log_failure(&image, &e);
+                    job.failed.fetch_add(1, Ordering::Relaxed);
+                }
+            }
+        }
+        job.processed.fetch_add(1, Ordering::Relaxed);
+    }
+    save_database(&database.read().unwrap()).map_err(|e| e.to_string())?;
+    Ok(format!("added {} images", added))
+}
+
+/// Removes images that no longer exist, for `/api/index/check`.
+fn check_images(database: &RwLock<Database>, job: &jobs::Job) -> Result<String, String> {
+    let paths: Vec<String> = database
+        .read()
+        .unwrap()
+        .embeddings
+        .keys()
+        .cloned()
+        .collect();
+    job.total.store(paths.len(), Ordering::Relaxed);
+    let mut to_remove = Vec::new();
+    for path in paths {
+        if !database::key_path(&path).exists() {
+            to_remove.push(path);
+        }
+        job.processed.fetch_add(1, Ordering::Relaxed);
+    }
+    let mut database = database.write().unwrap();
+    for path in to_remove.iter() {
+        database.remove(path);
+    }
+    save_database(&database).map_err(|e| e.to_string())?;
+    Ok(format!("removed {} invalid entries", to_remove.len()))
+}
+
+/// Options controlling which of the ranked results are returned.
+#[derive(Debug, Clone)]
+struct ResultFilter {
+    /// Number of results to skip, for paging.
+    offset: usize,
+    limit: usize,
+    min_score: Option<f32>,
+    auto_cutoff: bool,
+    /// Only keep images carrying all of these tags.
+    tags: Vec<String>,
+}
+
+impl Default for ResultFilter {
+    fn default() -> Self {
+        Self {
+            offset: 0,
+            limit: 50,
+            min_score: None,
+            auto_cutoff: false,
+            tags: Vec::new(),
+        }
+    }
 }
 
+impl ResultFilter {
+    /// Reads the `offset`, `limit`, `min_score`, `cutoff=auto` and `tag` query
+    /// parameters.
+    fn from_params(params: &HttpParams) -> Result<Self, api::ApiError> {
+        let mut filter = ResultFilter::default();
+        if let Some(offset) = api::optional(params, "offset")? {
+            filter.offset = offset;
+        }
+        filter.limit = api::limit(params, filter.limit)?;
+        filter.min_score = api::optional(params, "min_score")?;
+        filter.auto_cutoff = params.get("cutoff").map(String::as_str) == Some("auto");
+        if let Some(tags) = params.get("tag") {
+            filter.tags = tag::parse_tags(tags);
+        }
+        Ok(filter)
+    }
+
+    /// Filters and pages results sorted by descending score. Also returns how
+    /// many results matched in total.
+    fn apply<'a>(&self, mut result: Vec<Hit<'a>>) -> (Vec<Hit<'a>>, usize) {
+        if !self.tags.is_empty() {
+            result.retain(|hit| hit.database.has_tags(hit.path, &self.tags));
+        }
+        if let Some(min_score) = self.min_score {
+            let len = result.partition_point(|hit| hit.score >= min_score);
+            result.truncate(len);
+        }
+        if self.auto_cutoff {
+            // judged on the first page only, so that every page agrees on it
+            let scores: Vec<f32> = result
+                .iter()
+                .take(self.limit)
+                .map(|hit| hit.score)
+                .collect();
+            result.truncate(relevance_cutoff(&scores));
+        }
+        let total = result.len();
+        result.drain(..self.offset.min(total));
+        result.truncate(self.limit);
+        (result, total)
+    }
+}
+
+type SharedDatabase = (String, Arc<RwLock<Database>>);
+
+/// The collections `serve` answers for, by name, the default one first.
+/// Images are found by id in any of them.
+struct Collections(Vec<SharedDatabase>);
+
+impl Collections {
+    /// The collection called `name`, or the default one.
+    fn get(&self, name: Option<&str>) -> Result<&SharedDatabase, ApiError> {
+        match name {
+            None => Ok(&self.0[0]),
+            Some(name) => self
+                .0
+                .iter()
+                .find(|(n, _)| n == name)
+                .ok_or_else(|| ApiError::new(404, format!("unknown collection '{}'", name))),
+        }
+    }
+
+    /// The collection named by the `collection` parameter, or the default one.
+    fn one(&self, params: &HttpParams) -> Result<&SharedDatabase, ApiError> {
+        self.get(params.get("collection").map(String::as_str))
+    }
+
+    /// The collections named by the comma separated `collection` parameter,
+    /// all of them for `*`, or the default one.
+    fn select(&self, params: &HttpParams) -> Result<Vec<&SharedDatabase>, ApiError> {
+        match params.get("collection").map(|names| names.trim()) {
+            None | Some("") => Ok(vec![&self.0[0]]),
+            Some("*") => Ok(self.0.iter().collect()),
+            Some(names) => {
+                let mut selected: Vec<&SharedDatabase> = Vec::new();
+                for name in names.split(',') {
+                    let collection = self.get(Some(name.trim()))?;
+                    if !selected.iter().any(|(n, _)| *n == collection.0) {
+                        selected.push(collection);
+                    }
+                }
+                Ok(selected)
+            }
+        }
+    }
+
+    /// Looks up the path of an image by id in every collection.
+    fn path_of(&self, id: &str) -> Option<String> {
+        self.0
+            .iter()
+            .find_map(|(_, database)| database.read().unwrap().path_of(id).cloned())
+    }
+
+    /// Looks up an image by id in every collection, returning the name of
+    /// the collection that has it and its path.
+    fn image_of(&self, id: &str) -> Option<(String, String)> {
+        self.0.iter().find_map(|(name, database)| {
+            let path = database.read().unwrap().path_of(id)?.clone();
+            Some((name.clone(), path))
+        })
+    }
+
+    /// Looks up the path of an image by id in every collection, together
+    /// with the thumbnail cache of the collection that has it.
+    fn thumbnail_of(&self, id: &str) -> Option<(String, std::path::PathBuf)> {
+        self.0.iter().find_map(|(_, database)| {
+            let database = database.read().unwrap();
+            let path = database.path_of(id)?.clone();
+            Some((path, thumbnail::cache_dir(database.path())))
+        })
+    }
+}
+
+/// Takes read locks on collections that are ranked together.
+fn read_all<'a>(selected: &[&'a SharedDatabase]) -> Vec<(&'a str, RwLockReadGuard<'a, Database>)> {
+    selected
+        .iter()
+        .map(|(name, database)| (name.as_str(), database.read().unwrap()))
+        .collect()
+}
+
+/// Finds where similarity drops sharply in scores sorted descending, and
+/// returns the number of results before the drop. If no gap stands out from
+/// the others, all results are kept.
+fn relevance_cutoff(scores: &[f32]) -> usize {
+    // the knee must be this many times larger than the median gap
+    const GAP_RATIO: f32 = 3.0;
+    // and large enough not to be noise between near-identical scores
+    const MIN_GAP: f32 = 0.01;
+    if scores.len() < 3 {
+        return scores.len();
+    }
+    let gaps: Vec<f32> = scores.windows(2).map(|w| w[0] - w[1]).collect();
+    let (knee, max_gap) =
+        gaps.iter().enumerate().fold(
+            (0, f32::MIN),
+            |(i, max), (j, &gap)| {
+                if gap > max {
+                    (j, gap)
+                } else {
+                    (i, max)
+                }
+            },
+        );
+    let mut sorted_gaps = gaps.clone();
+    sorted_gaps.sort_by(|a, b| a.total_cmp(b));
+    let median_gap = sorted_gaps[sorted_gaps.len() / 2];
+    if max_gap >= MIN_GAP && max_gap >= GAP_RATIO * median_gap {
+        knee + 1
+    } else {
+        scores.len()
+    }
+}
+
+/// Prints the matches for `text`, naming their collection when several
+/// collections are searched.
 fn command_find_image(
-    database: &Database,
+    databases: &[(&str, &Database)],
     model: &model::ClipTextTransformer,
     tokenizer: &Tokenizer,
     text: &str,
-) {
-    let result = find_image(database, model, tokenizer, text).expect("failed to find image");
-    for (path, similarity) in result.iter().take(50) {
-        println!("{:.4} {}", similarity, path);
+    filter: &ResultFilter,
+    format: output::Format,
+) -> error::Result<()> {
+    let result = find_image(databases, model, tokenizer, text)?;
+    let (result, _) = filter.apply(result);
+    let rows: Vec<_> = result
+        .iter()
+        .map(|hit| output::FoundImage {
+            score: hit.score,
+            collection: hit.collection,
+            path: hit.path,
+            show_collection: databases.len() > 1,
+        })
+        .collect();
+    Ok(output::print_rows(format, &rows)?)
+}
+
+fn image_entry<'a>(
+    collection: &'a str,
+    database: &'a Database,
+    path: &'a str,
+) -> output::ImageEntry<'a> {
+    output::ImageEntry {
+        id: database::image_id(path),
+        collection,
+        path,
+        tags: database.tags.get(path).map_or_else(Vec::new, |tags| {
+            tags.iter().map(|(t, _)| t.as_str()).collect()
+        }),
+    }
+}
+
+/// Removes images that no longer exist, listing them as they were indexed.
+fn command_check(
+    database: &mut Database,
+    collection: &str,
+    format: output::Format,
+) -> std::io::Result<()> {
+    let mut to_remove = Vec::new();
+    let bar = logging::progress_bar(database.len() as u64, "checking");
+    for path in database.embeddings.keys() {
+        bar.inc(1);
+        if !database::key_path(path).exists() {
+            to_remove.push(path.clone());
+        }
+    }
+    bar.finish_and_clear();
+    let rows: Vec<_> = to_remove
+        .iter()
+        .map(|path| image_entry(collection, database, path))
+        .collect();
+    output::print_rows(format, &rows)?;
+    for path in to_remove.iter() {
+        database.remove(path);
+    }
+    log::info!(removed = to_remove.len(); "check finished");
+    Ok(())
+}
+
+fn command_find_dupes(database: &Database, threshold: f32, phash: bool) {
+    let groups = dupes::find_duplicates(&database.embeddings, threshold, phash);
+    for (i, group) in groups.iter().enumerate() {
+        println!("group {} ({} images)", i + 1, group.len());
+        for image in group {
+            let resolution = match (image.width, image.height) {
+                (Some(w), Some(h)) => format!("{}x{}", w, h),
+                _ => "?".to_string(),
+            };
+            let size = image.size.map_or("?".to_string(), format_size);
+            println!("  {:>11} {:>10}  {}", resolution, size, image.path);
+        }
+    }
+    println!("found {} groups", groups.len());
+}
+
+fn format_size(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["B", "KiB", "MiB", "GiB"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
     }
+    format!("{:.1} {}", size, UNITS[unit])
 }
 
 fn normalize(x: &[f32]) -> Vec<f32> {
@@ -184,129 +764,1362 @@ fn normalize(x: &[f32]) -> Vec<f32> {
 }
 
 fn dot_product(x: &[f32], y: &[f32]) -> f32 {
-    x.iter().zip(y).map(|(x, y)| x * y).sum()
+    // eight independent accumulators so the compiler can keep them in one SIMD register
+    let mut lanes = [0f32; 8];
+    let xs = x.chunks_exact(8);
+    let ys = y.chunks_exact(8);
+    let tail: f32 = xs
+        .remainder()
+        .iter()
+        .zip(ys.remainder())
+        .map(|(x, y)| x * y)
+        .sum();
+    for (x, y) in xs.zip(ys) {
+        for ((lane, x), y) in lanes.iter_mut().zip(x).zip(y) {
+            *lane += x * y;
+        }
+    }
+    lanes.iter().sum::<f32>() + tail
 }
 
+/// Serves an indexed image by id. Raw filesystem paths are refused, so only
+/// files that were added to the database can be read through the server.
+/// Originals are streamed from disk, with conditional and range requests.
 fn api_get_image(
-    _uri: HttpUri,
-    _headers: HttpHeaders,
-    params: HttpParams,
-    _body: HttpBody,
-) -> Result<HttpResponse, Box<dyn std::error::Error>> {
-    use std::fs::read;
-    let image_path = params.get("path").ok_or("missing parameter 'path'")?.trim();
-    let (content_type, content) = match get_extension(image_path).as_str() {
-        "png" => {
-            let content = read(image_path)?;
-            ("image/png", content)
-        }
-        "jpeg" | "jpg" => {
-            let content = read(image_path)?;
-            ("image/jpeg", content)
+    collections: &Collections,
+    headers: &HttpHeaders,
+    params: &HttpParams,
+) -> ApiResult {
+    use std::io::{Seek, SeekFrom};
+    if params.contains_key("path") {
+        return Err(ApiError::new(403, "images must be requested by id"));
+    }
+    let id = api::required(params, "id")?;
+    let Some(image_path) = collections.path_of(id) else {
+        return Err(ApiError::new(404, "image not found"));
+    };
+    let image_path = image_path.as_str();
+    let content_type = match get_extension(image_path).as_str() {
+        "png" => "image/png",
+        "jpeg" | "jpg" => "image/jpeg",
+        "heic" | "heif" => "image/jpeg",
+        _ => return Err(ApiError::new(403, "not an image")),
+    };
+    let mut file = match std::fs::File::open(database::key_path(image_path)) {
+        Ok(file) => file,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            return Err(ApiError::new(404, "image file is missing"))
         }
+        Err(e) => return Err(e.into()),
+    };
+    let metadata = file.metadata()?;
+    let validators = http::Validators::new(&metadata);
+    let response = HttpResponse::builder()
+        .add_header("Content-Type", content_type)
+        .add_header("ETag", &validators.etag)
+        .add_header("Last-Modified", &validators.last_modified)
+        .add_header("Cache-Control", "private, no-cache");
+    if validators.not_modified(headers) {
+        return Ok(response.set_code(304).build());
+    }
+
+    let extension = get_extension(image_path);
+    if extension == "heic" || extension == "heif" {
+        // browsers cannot show HEIF, so it is converted and sent whole
         #[cfg(not(feature = "heif"))]
-        "heic" | "heif" => return Err("heif support not enabled".into()),
+        return Err(ApiError::new(415, "heif support not enabled"));
         #[cfg(feature = "heif")]
-        "heic" | "heif" => {
+        {
             let img = load_heif(image_path)?;
             let mut buffer = std::io::Cursor::new(Vec::new());
             img.write_to(&mut buffer, image::ImageOutputFormat::Jpeg(90))?;
-            ("image/jpeg", buffer.into_inner())
+            return Ok(response
+                .set_code(200)
+                .set_payload_raw(buffer.into_inner())
+                .build());
         }
-        _ => return Err("invalid image path".into()),
+    }
+
+    let len = metadata.len();
+    let range = if validators.range_applies(headers) {
+        http::parse_range(headers.get("range"), len)
+    } else {
+        http::Range::Full
     };
+    let response = response.add_header("Accept-Ranges", "bytes");
+    Ok(match range {
+        http::Range::Full => response.set_code(200).set_payload_stream(file, len).build(),
+        http::Range::Partial(start, end) => {
+            file.seek(SeekFrom::Start(start))?;
+            response
+                .set_code(206)
+                .add_header("Content-Range", format!("bytes {}-{}/{}", start, end, len))
+                .set_payload_stream(file, end - start + 1)
+                .build()
+        }
+        http::Range::Unsatisfiable => response
+            .set_code(416)
+            .add_header("Content-Range", format!("bytes */{}", len))
+            .build(),
+    })
+}
+
+#[derive(serde::Deserialize)]
+struct IndexRequest {
+    path: String,
+    #[serde(default)]
+    thumbnails: bool,
+    /// Defaults to the default collection.
+    #[serde(default)]
+    collection: Option<String>,
+}
+
+#[derive(serde::Deserialize)]
+struct CheckRequest {
+    #[serde(default)]
+    collection: Option<String>,
+}
+
+#[derive(serde::Deserialize)]
+struct DupesRequest {
+    #[serde(default)]
+    threshold: Option<f32>,
+    #[serde(default)]
+    phash: bool,
+    #[serde(default)]
+    collection: Option<String>,
+}
+
+fn job_response(job: &jobs::Job) -> ApiResult {
+    let mut response = api::json_response(&serde_json::json!({
+        "version": api::API_VERSION,
+        "job": job.status(),
+    }))?;
+    response.code = 202;
+    Ok(response)
+}
+
+/// Starts indexing a directory on the server in the background. Only
+/// directories under the configured roots of the collection may be indexed.
+fn api_index_add(
+    collections: &Collections,
+    roots: &BTreeMap<String, Vec<std::path::PathBuf>>,
+    model: &Arc<model::ClipVisionTransformer>,
+    filter: &Arc<scan::Filter>,
+    jobs: &jobs::Jobs,
+    headers: &HttpHeaders,
+    body: &HttpBody,
+) -> ApiResult {
+    let request: IndexRequest = api::json_body(headers, body)?;
+    let (name, database) = collections.get(request.collection.as_deref())?;
+    let not_a_directory = || ApiError::new(400, format!("not a directory: {}", request.path));
+    let canonical = std::fs::canonicalize(&request.path).map_err(|_| not_a_directory())?;
+    if !canonical.is_dir() {
+        return Err(not_a_directory());
+    }
+    let roots = &roots[name];
+    if roots.is_empty() {
+        return Err(ApiError::new(
+            403,
+            format!("no roots are configured for collection '{}'", name),
+        ));
+    }
+    if !roots.iter().any(|root| canonical.starts_with(root)) {
+        return Err(ApiError::new(
+            403,
+            format!(
+                "not under a root of collection '{}': {}",
+                name, request.path
+            ),
+        ));
+    }
+    let path = canonical.to_str().ok_or_else(not_a_directory)?.to_string();
+    let (database, model, filter) = (database.clone(), model.clone(), filter.clone());
+    let thumbnails = request.thumbnails;
+    let job = jobs
+        .start(jobs::JobKind::Add, Some(path.clone()), move |job| {
+            index_images(&database, &model, &path, &filter, thumbnails, job)
+        })
+        .ok_or_else(|| ApiError::new(409, "another job is still running"))?;
+    job_response(&job)
+}
+
+/// Starts removing missing images from the database in the background.
+fn api_index_check(
+    collections: &Collections,
+    jobs: &jobs::Jobs,
+    headers: &HttpHeaders,
+    body: &HttpBody,
+) -> ApiResult {
+    let request: CheckRequest = api::json_body(headers, body)?;
+    let (_, database) = collections.get(request.collection.as_deref())?;
+    let database = database.clone();
+    let job = jobs
+        .start(jobs::JobKind::Check, None, move |job| {
+            check_images(&database, job)
+        })
+        .ok_or_else(|| ApiError::new(409, "another job is still running"))?;
+    job_response(&job)
+}
+
+/// Starts looking for near-duplicates in the background. Comparing every
+/// pair takes long on a large library, so the groups are reported as the
+/// result of the job.
+fn api_dupes(
+    collections: &Collections,
+    jobs: &jobs::Jobs,
+    headers: &HttpHeaders,
+    body: &HttpBody,
+) -> ApiResult {
+    let request: DupesRequest = api::json_body(headers, body)?;
+    let threshold = request.threshold.unwrap_or(DUPES_THRESHOLD);
+    if !dupes::valid_threshold(threshold) {
+        return Err(ApiError::new(
+            400,
+            "threshold must be greater than 0 and at most 1",
+        ));
+    }
+    let (_, database) = collections.get(request.collection.as_deref())?;
+    let database = database.clone();
+    let job = jobs
+        .start(jobs::JobKind::Dupes, None, move |job| {
+            // a copy, so indexing is not blocked while every pair is compared
+            let embeddings = database.read().unwrap().embeddings.clone();
+            let groups = dupes::find_duplicates(&embeddings, threshold, request.phash);
+            job.set_result(serde_json::json!({ "total": groups.len(), "groups": groups }));
+            Ok(format!("found {} groups of duplicates", groups.len()))
+        })
+        .ok_or_else(|| ApiError::new(409, "another job is still running"))?;
+    job_response(&job)
+}
+
+/// Shows a file selected in the desktop's file manager.
+fn reveal(path: &str) -> std::io::Result<()> {
+    use std::process::Command;
+    #[cfg(windows)]
+    let mut command = {
+        use std::os::windows::process::CommandExt;
+        let path = std::path::absolute(database::key_path(path))?;
+        // explorer does its own parsing, the quotes must follow the comma, and
+        // a quote in the path could not be escaped
+        let path = path
+            .to_str()
+            .filter(|path| !path.contains('"'))
+            .ok_or_else(|| std::io::Error::other("cannot pass this path to explorer"))?;
+        let mut command = Command::new("explorer");
+        command.raw_arg(format!("/select,\"{}\"", path));
+        command
+    };
+    #[cfg(target_os = "macos")]
+    let mut command = {
+        let mut command = Command::new("open");
+        command.arg("-R").arg(database::key_path(path));
+        command
+    };
+    #[cfg(not(any(windows, target_os = "macos")))]
+    let mut command = {
+        let path = database::key_path(path);
+        let folder = path.parent().unwrap_or(".".as_ref());
+        let mut command = Command::new("xdg-open");
+        command.arg(folder);
+        command
+    };
+    let mut child = command.spawn()?;
+    // reap the launcher once it exits
+    std::thread::spawn(move || child.wait());
+    Ok(())
+}
+
+#[derive(serde::Deserialize)]
+struct OpenFolderRequest {
+    id: String,
+}
+
+/// Opens the folder of an indexed image on the server's desktop, which only
+/// makes sense for a browser running on the same machine.
+fn api_open_folder(collections: &Collections, headers: &HttpHeaders, body: &HttpBody) -> ApiResult {
+    let local = headers
+        .get("X-47-Remote-Addr")
+        .and_then(|addr| addr.parse::<IpAddr>().ok())
+        .is_some_and(|addr| addr.is_loopback());
+    if !local {
+        return Err(ApiError::new(
+            403,
+            "folders can only be opened from the machine running the server",
+        ));
+    }
+    let request: OpenFolderRequest = api::json_body(headers, body)?;
+    let Some(path) = collections.path_of(&request.id) else {
+        return Err(ApiError::new(404, "image not found"));
+    };
+    reveal(&path).map_err(|e| ApiError::new(501, format!("cannot open folder: {}", e)))?;
+    api::json_response(&serde_json::json!({ "version": api::API_VERSION }))
+}
+
+/// Reports one job by `id`, or all jobs.
+fn api_jobs(jobs: &jobs::Jobs, params: &HttpParams) -> ApiResult {
+    match api::optional(params, "id")? {
+        Some(id) => {
+            let job = jobs
+                .get(id)
+                .ok_or_else(|| ApiError::new(404, "unknown job"))?;
+            api::json_response(&serde_json::json!({
+                "version": api::API_VERSION,
+                "job": job.status(),
+                "result": job.result(),
+            }))
+        }
+        None => {
+            let statuses: Vec<_> = jobs.list().iter().map(|job| job.status()).collect();
+            api::json_response(&serde_json::json!({
+                "version": api::API_VERSION,
+                "jobs": statuses,
+            }))
+        }
+    }
+}
+
+/// The user data of everyone, with the credentials that tell whose a
+/// request may use.
+struct SharedUserData {
+    data: Mutex<UserData>,
+    auth: Arc<auth::Auth>,
+    /// Searches were recorded since the file was last written. History is
+    /// saved every [`HISTORY_SAVE_INTERVAL`] rather than on every query.
+    history_unsaved: AtomicBool,
+}
+
+/// How often recorded searches are written to `userdata.json`.
+const HISTORY_SAVE_INTERVAL: std::time::Duration = std::time::Duration::from_secs(30);
+
+/// Records a search in the history of whoever made the request, to be
+/// written with the next save.
+fn record_search(userdata: &SharedUserData, headers: &HttpHeaders, query: &str, time: u64) {
+    let user = userdata.auth.user_name(headers);
+    userdata
+        .data
+        .lock()
+        .unwrap()
+        .user(&user)
+        .record_search(query, time);
+    userdata.history_unsaved.store(true, Ordering::Relaxed);
+}
+
+/// Writes recorded searches now and then, for as long as the server runs.
+fn save_history_periodically(userdata: &SharedUserData) {
+    loop {
+        std::thread::sleep(HISTORY_SAVE_INTERVAL);
+        let data = userdata.data.lock().unwrap();
+        if userdata.history_unsaved.swap(false, Ordering::Relaxed) {
+            if let Err(e) = userdata::save_userdata(&data) {
+                log::warn!("failed to save search history: {}", e);
+                userdata.history_unsaved.store(true, Ordering::Relaxed);
+            }
+        }
+    }
+}
+
+/// Reads the user data of whoever made the request.
+fn read_userdata<T>(
+    userdata: &SharedUserData,
+    headers: &HttpHeaders,
+    read: impl FnOnce(&UserStore) -> T,
+) -> T {
+    let user = userdata.auth.user_name(headers);
+    let userdata = userdata.data.lock().unwrap();
+    match userdata.users.get(&user) {
+        Some(store) => read(store),
+        None => read(&UserStore::default()),
+    }
+}
+
+/// Changes the user data of whoever made the request and saves it.
+fn update_userdata<T>(
+    userdata: &SharedUserData,
+    headers: &HttpHeaders,
+    change: impl FnOnce(&mut UserStore) -> T,
+) -> Result<T, ApiError> {
+    let user = userdata.auth.user_name(headers);
+    let mut data = userdata.data.lock().unwrap();
+    let result = change(data.user(&user));
+    userdata::save_userdata(&data)?;
+    // the file now holds the history too
+    userdata.history_unsaved.store(false, Ordering::Relaxed);
+    Ok(result)
+}
+
+fn not_empty<'a>(value: &'a str, name: &str) -> Result<&'a str, ApiError> {
+    match value.trim() {
+        "" => Err(ApiError::new(400, format!("'{}' must not be empty", name))),
+        value => Ok(value),
+    }
+}
+
+#[derive(serde::Deserialize)]
+struct SavedSearchRequest {
+    name: String,
+    #[serde(default)]
+    query: String,
+}
+
+/// Lists the saved searches of the user.
+fn api_saved(userdata: &SharedUserData, headers: &HttpHeaders) -> ApiResult {
+    let saved: Vec<_> = read_userdata(userdata, headers, |store| {
+        store
+            .saved
+            .iter()
+            .map(|(name, query)| serde_json::json!({ "name": name, "query": query }))
+            .collect()
+    });
+    api::json_response(&serde_json::json!({
+        "version": api::API_VERSION,
+        "saved": saved,
+    }))
+}
+
+/// Saves a search under a name, replacing one saved under the same name, or
+/// deletes it.
+fn api_saved_change(
+    userdata: &SharedUserData,
+    headers: &HttpHeaders,
+    body: &HttpBody,
+    delete: bool,
+) -> ApiResult {
+    let request: SavedSearchRequest = api::json_body(headers, body)?;
+    let name = not_empty(&request.name, "name")?;
+    if delete {
+        let deleted = update_userdata(userdata, headers, |store| {
+            store.saved.remove(name).is_some()
+        })?;
+        if !deleted {
+            return Err(ApiError::new(404, "unknown saved search"));
+        }
+    } else {
+        let query = not_empty(&request.query, "query")?;
+        update_userdata(userdata, headers, |store| {
+            store.saved.insert(name.to_string(), query.to_string())
+        })?;
+    }
+    api_saved(userdata, headers)
+}
+
+#[derive(serde::Deserialize)]
+struct FavouriteRequest {
+    collection: String,
+    id: String,
+}
+
+/// Lists the favourites collections of the user, or with `collection` the
+/// images starred into it, in the order they were added.
+fn api_favourites(
+    collections: &Collections,
+    userdata: &SharedUserData,
+    headers: &HttpHeaders,
+    params: &HttpParams,
+) -> ApiResult {
+    let started = std::time::Instant::now();
+    let Some(collection) = params.get("collection") else {
+        let collections: Vec<_> = read_userdata(userdata, headers, |store| {
+            store
+                .favourites
+                .iter()
+                .map(|(name, paths)| serde_json::json!({ "name": name, "count": paths.len() }))
+                .collect()
+        });
+        return api::json_response(&serde_json::json!({
+            "version": api::API_VERSION,
+            "collections": collections,
+        }));
+    };
+    let offset = api::optional(params, "offset")?.unwrap_or(0);
+    let limit = api::limit(params, ResultFilter::default().limit)?;
+    let guards = read_all(&collections.0.iter().collect::<Vec<_>>());
+    // images removed from the index stay starred, in case they come back
+    let paths: Vec<(&str, &Database, &String)> = read_userdata(userdata, headers, |store| {
+        store
+            .favourites
+            .get(collection)
+            .map_or_else(Vec::new, |favourites| {
+                favourites
+                    .iter()
+                    .filter_map(|favourite| {
+                        guards
+                            .iter()
+                            .filter(|(name, _)| {
+                                favourite.library.as_deref().is_none_or(|l| l == *name)
+                            })
+                            .find_map(|(name, database)| {
+                                let (path, _) =
+                                    database.embeddings.get_key_value(&favourite.path)?;
+                                Some((*name, &**database, path))
+                            })
+                    })
+                    .collect()
+            })
+    });
+    let results = paths
+        .iter()
+        .skip(offset)
+        .take(limit)
+        .map(|&(name, database, path)| api::ResultItem::new(name, database, path, None))
+        .collect();
+    api::json_response(&api::ResultPage {
+        version: api::API_VERSION,
+        results,
+        total: paths.len(),
+        took_ms: api::took_ms(started),
+    })
+}
+
+/// Stars an image into a collection, creating the collection if needed, or
+/// unstars it.
+fn api_favourites_change(
+    collections: &Collections,
+    userdata: &SharedUserData,
+    headers: &HttpHeaders,
+    body: &HttpBody,
+    remove: bool,
+) -> ApiResult {
+    let request: FavouriteRequest = api::json_body(headers, body)?;
+    let collection = not_empty(&request.collection, "collection")?;
+    let Some((library, path)) = collections.image_of(&request.id) else {
+        return Err(ApiError::new(404, "image not found"));
+    };
+    let changed = update_userdata(userdata, headers, |store| {
+        if remove {
+            store.unstar(collection, &library, &path)
+        } else {
+            store.star(collection, &library, &path)
+        }
+    })?;
+    api::json_response(&serde_json::json!({
+        "version": api::API_VERSION,
+        "changed": changed,
+    }))
+}
+
+/// Searches with an uploaded image instead of text. The body is either the
+/// raw image or a multipart form carrying it; it is decoded and embedded in
+/// memory and never written to disk.
+fn api_search_by_upload(
+    collections: &Collections,
+    model: &model::ClipVisionTransformer,
+    headers: &HttpHeaders,
+    params: &HttpParams,
+    body: Option<&[u8]>,
+) -> ApiResult {
+    let started = std::time::Instant::now();
+    let body = body.filter(|body| !body.is_empty());
+    let upload = match (headers.get("content-type"), body) {
+        (_, None) => None,
+        (Some(content_type), Some(body)) if content_type.starts_with("multipart/form-data") => {
+            http::multipart_file(content_type, body)
+        }
+        (_, body) => body,
+    };
+    let Some(upload) = upload else {
+        return Err(ApiError::new(400, "missing image"));
+    };
+    let img = decode_image(upload)
+        .map_err(|e| ApiError::new(415, format!("unsupported image: {}", e)))?;
+    let feature = encode_image(model, &img)?;
+    let guards = read_all(&collections.select(params)?);
+    let databases: Vec<_> = guards.iter().map(|(name, db)| (*name, &**db)).collect();
+    let (items, total) = ResultFilter::from_params(params)?.apply(rank(&databases, &feature));
+    api::results_response(&items, total, started)
+}
+
+/// Serves a cached thumbnail of an indexed image, revalidated by its ETag.
+fn api_thumbnail(
+    collections: &Collections,
+    hashes: &thumbnail::HashCache,
+    headers: &HttpHeaders,
+    params: &HttpParams,
+) -> ApiResult {
+    let id = api::required(params, "id")?;
+    let Some((image_path, cache)) = collections.thumbnail_of(id) else {
+        return Err(ApiError::new(404, "image not found"));
+    };
+    let image_path = image_path.as_str();
+    let size =
+        api::optional(params, "size")?.map_or(thumbnail::DEFAULT_SIZE, thumbnail::round_size);
+    let format = match params.get("format").map(String::as_str) {
+        Some("jpeg") | Some("jpg") => thumbnail::Format::Jpeg,
+        #[cfg(feature = "webp")]
+        Some("webp") => thumbnail::Format::WebP,
+        #[cfg(feature = "webp")]
+        None if headers
+            .get("accept")
+            .is_some_and(|accept| accept.contains("image/webp")) =>
+        {
+            thumbnail::Format::WebP
+        }
+        None => thumbnail::Format::Jpeg,
+        Some(_) => return Err(ApiError::new(400, "unsupported thumbnail format")),
+    };
+    let hash = hashes.get(image_path)?;
+    let etag = format!("\"{:016x}-{}-{}\"", hash, size, format.content_type());
+    if headers.get("if-none-match") == Some(&etag) {
+        return Ok(HttpResponse::builder()
+            .set_code(304)
+            .add_header("ETag", etag)
+            .build());
+    }
+    let content = thumbnail::get_or_create(&cache, image_path, hash, size, format)
+        .map_err(ApiError::internal)?;
     Ok(HttpResponse::builder()
         .set_code(200)
-        .add_header("Content-Type", content_type)
+        .add_header("Content-Type", format.content_type())
+        .add_header("ETag", etag)
+        .add_header("Cache-Control", "private, max-age=3600")
         .set_payload_raw(content)
         .build())
 }
 
-fn main() -> tokenizer::Result<()> {
-    let mut database = load_database();
-    let arg1 = std::env::args().nth(1);
-    let arg2 = std::env::args().nth(2);
-
-    let arg1 = arg1.as_deref();
-    if arg1 == Some("add") && arg2.is_some() {
-        let weights =
-            unsafe { candle_core::safetensors::MmapedFile::new("clip/model.safetensors")? };
-        let weights = weights.deserialize()?;
-        let vb = VarBuilder::from_safetensors(vec![weights], DType::F32, &Device::Cpu);
-        let model = model::ClipVisionTransformer::new(vb, &model::Config::vision())?;
-        command_add_image(&mut database, &arg2.unwrap(), &model);
-        save_database(&database);
-        return Ok(());
-    } else if arg1 == Some("find") && arg2.is_some() {
-        let weights =
-            unsafe { candle_core::safetensors::MmapedFile::new("clip/model.safetensors")? };
-        let weights = weights.deserialize()?;
-        let vb = VarBuilder::from_safetensors(vec![weights], DType::F32, &Device::Cpu);
-        let model = model::ClipTextTransformer::new(vb, &model::Config::clip())?;
-        let tokenizer = Tokenizer::from_file("./clip/tokenizer.json")?;
-        command_find_image(&database, &model, &tokenizer, &arg2.unwrap());
-        return Ok(());
-    } else if arg1 == Some("check") {
-        let mut to_remove = Vec::new();
-        let mut last_print = std::time::Instant::now();
-        for (i, (path, _)) in database.iter().enumerate() {
-            if last_print.elapsed().as_secs() >= 1 {
-                println!("checking {}/{}", i + 1, database.len());
-                last_print += std::time::Duration::from_secs(1);
+/// Serves the web UI and the HTTP API until the server fails.
+/// Settings missing from the command line are taken from the config.
+fn command_serve(
+    config: &config::Config,
+    collections: &[config::Collection],
+    model_dir: &std::path::Path,
+    tokenizer: &std::path::Path,
+    args: cli::ServeArgs,
+) -> error::Result<()> {
+    let serve = &config.serve;
+    let port = args
+        .port
+        .or(serve.port)
+        .ok_or("no port given on the command line or in the config")?;
+    let bind = args
+        .bind
+        .or(serve.bind)
+        .unwrap_or(IpAddr::V4(Ipv4Addr::LOCALHOST));
+    let mut auth = auth::Auth::default();
+    auth.token = args.token.or_else(|| serve.token.clone());
+    auth.users = args.user;
+    for user in serve.users.iter() {
+        auth.users.push(auth::parse_user(user)?);
+    }
+    let (tls_cert, tls_key) = match args.tls_cert {
+        Some(_) => (args.tls_cert, args.tls_key),
+        None => (serve.tls_cert.clone(), serve.tls_key.clone()),
+    };
+    auth.secure = tls_cert.is_some();
+    // `--watch` adds to the default collection, and `watch = true` in the
+    // config watches the roots of every collection
+    let watch_roots: Vec<Vec<String>> = collections
+        .iter()
+        .enumerate()
+        .map(|(i, collection)| {
+            let mut roots = Vec::new();
+            if serve.watch {
+                roots.extend(collection.roots.iter().cloned());
             }
-            if !std::path::Path::new(path).exists() {
-                to_remove.push(path.clone());
+            if i == 0 {
+                roots.extend(args.watch.iter().cloned());
             }
-        }
-        for path in to_remove.iter() {
-            database.remove(path);
-        }
-        println!("removed {} invalid entries", to_remove.len());
-        save_database(&database);
-        return Ok(());
-    } else if arg1 == Some("serve") && arg2.is_some() {
-        let weights =
-            unsafe { candle_core::safetensors::MmapedFile::new("clip/model.safetensors")? };
-        let weights = weights.deserialize()?;
-        let vb = VarBuilder::from_safetensors(vec![weights], DType::F32, &Device::Cpu);
-        let model = model::ClipTextTransformer::new(vb, &model::Config::clip())?;
-        let tokenizer = Tokenizer::from_file("./clip/tokenizer.json")?;
-
-        let port: u16 = arg2.unwrap().parse()?;
-        let mut httpd = MinHttpd::new();
-
-        let database = Arc::new(database);
-        let model = Arc::new(model);
-        let tokenizer = Arc::new(tokenizer);
-
-        httpd.route_fn("/api/getImage", api_get_image);
-
-        httpd.route(
-            "/api/search",
-            Box::new(move |_, _, params, _| {
-                let query_text = params.get("text").ok_or("missing parameter 'text'")?.trim();
-
-                let query_result = find_image(&database, &model, &tokenizer, query_text)
-                    .map_err(|e| format!("failed to query: {}", e))?;
-
-                let first_50_items = query_result.iter().take(50).collect::<Vec<_>>();
-
-                Ok(HttpResponse::builder()
-                    .set_code(200)
-                    .add_header("Content-Type", "application/json")
-                    .set_payload(serde_json::to_string(&first_50_items)?)
-                    .build())
-            }),
+            roots
+        })
+        .collect();
+    // resolved once, so that links and `..` cannot lead `/api/index/add` out
+    let index_roots: BTreeMap<String, Vec<std::path::PathBuf>> = collections
+        .iter()
+        .map(|collection| {
+            let roots = collection
+                .roots
+                .iter()
+                .filter_map(|root| match std::fs::canonicalize(root) {
+                    Ok(root) => Some(root),
+                    Err(e) => {
+                        log::warn!(path = root.as_str(); "cannot resolve root: {}", e);
+                        None
+                    }
+                })
+                .collect();
+            (collection.name.clone(), roots)
+        })
+        .collect();
+    let filter = Arc::new(config.scan_filter()?);
+    let addr = SocketAddr::new(bind, port);
+    if !bind.is_loopback() && !auth.is_enabled() {
+        log::warn!(
+            "serving on {} without --token or --user, anyone on the network can browse your images",
+            addr
         );
+    }
 
-        httpd.route_static("", "text/html", include_str!("index.html").to_string());
+    let (model, tokenizer) = load_text_model(model_dir, tokenizer)?;
+    let vision_model = load_vision_model(model_dir)?;
+    let mut httpd = MinHttpd::new();
+    let auth = Arc::new(auth);
+    if auth.is_enabled() {
+        httpd.set_guard(auth.clone().into_guard());
+    }
+    httpd.set_max_body_size(
+        MAX_UPLOAD_SIZE,
+        Box::new(|_| ApiError::new(413, "request body too large").into_response()),
+    );
 
-        println!("starting server at http://127.0.0.1:{}", port);
-        httpd
-            .serve(SocketAddrV4::new(Ipv4Addr::new(127, 0, 0, 1), port))
-            .unwrap();
+    // jobs started over HTTP update the databases while they are being searched
+    let userdata = Arc::new(SharedUserData {
+        data: Mutex::new(userdata::load_userdata(&collections[0].database)?),
+        auth,
+        history_unsaved: AtomicBool::new(false),
+    });
+    let saving_userdata = userdata.clone();
+    std::thread::spawn(move || save_history_periodically(&saving_userdata));
+    let collections = Arc::new(Collections(
+        collections
+            .iter()
+            .map(|collection| {
+                let database = load_database(&collection.database)?;
+                Ok((collection.name.clone(), Arc::new(RwLock::new(database))))
+            })
+            .collect::<error::Result<_>>()?,
+    ));
+    let model = Arc::new(model);
+    let tokenizer = Arc::new(tokenizer);
+    let vision_model = Arc::new(vision_model);
+    let jobs = Arc::new(jobs::Jobs::default());
+
+    for (roots, (_, database)) in watch_roots.iter().zip(collections.0.iter()) {
+        if roots.is_empty() {
+            continue;
+        }
+        let roots = add_roots(&mut database.write().unwrap(), roots)?;
+        // the web UI shows thumbnails, so new images get them right away
+        let thumbnails = thumbnail::cache_dir(database.read().unwrap().path());
+        let watch = watch::Watch::new(&roots, (*filter).clone(), Some(thumbnails))?;
+        let (database, model) = (database.clone(), vision_model.clone());
+        std::thread::spawn(move || watch.run(&database, &model));
+    }
+
+    let image_collections = collections.clone();
+    httpd.route(
+        "/api/getImage",
+        api::handler(move |_, headers, params, _| {
+            api_get_image(&image_collections, &headers, &params)
+        }),
+    );
+
+    let thumbnail_collections = collections.clone();
+    let hashes = thumbnail::HashCache::default();
+    httpd.route(
+        "/api/thumbnail",
+        api::handler(move |_, headers, params, _| {
+            api_thumbnail(&thumbnail_collections, &hashes, &headers, &params)
+        }),
+    );
+
+    // registered before /api/search, which is a prefix of it
+    let upload_collections = collections.clone();
+    let upload_model = vision_model.clone();
+    httpd.route(
+        "/api/searchByUpload",
+        api::handler(move |_, headers, params, body| {
+            api_search_by_upload(
+                &upload_collections,
+                &upload_model,
+                &headers,
+                &params,
+                body.as_deref(),
+            )
+        }),
+    );
+
+    let search_collections = collections.clone();
+    let search_userdata = userdata.clone();
+    httpd.route(
+        "/api/search",
+        api::handler(move |_, headers, params, _| {
+            let started = std::time::Instant::now();
+            let query_text = api::required(&params, "text")?;
+            let filter = ResultFilter::from_params(&params)?;
+            let guards = read_all(&search_collections.select(&params)?);
+            // later pages of the same search are not new history
+            if filter.offset == 0 && !query_text.is_empty() {
+                let now = std::time::SystemTime::now()
+                    .duration_since(std::time::UNIX_EPOCH)
+                    .map_or(0, |d| d.as_secs());
+                record_search(&search_userdata, &headers, query_text, now);
+            }
+
+            let databases: Vec<_> = guards.iter().map(|(name, db)| (*name, &**db)).collect();
+            let query_result =
+                find_image(&databases, &model, &tokenizer, query_text).map_err(|e| match e {
+                    Error::Query(message) => ApiError::new(400, message),
+                    e => ApiError::internal(format!("failed to query: {}", e)),
+                })?;
+
+            let (items, total) = filter.apply(query_result);
+            api::results_response(&items, total, started)
+        }),
+    );
+
+    let dupes_collections = collections.clone();
+    let dupes_jobs = jobs.clone();
+    httpd.route(
+        "/api/dupes",
+        api::handler(move |_, headers, _, body| {
+            api_dupes(&dupes_collections, &dupes_jobs, &headers, &body)
+        }),
+    );
+
+    let topics_collections = collections.clone();
+    httpd.route(
+        "/api/topics",
+        api::handler(move |_, _, params, _| {
+            let (_, database) = topics_collections.one(&params)?;
+            let topics_database = database.read().unwrap();
+            let mut counts = vec![0; topics_database.topics.len()];
+            // assignments come from the file and may be stale, skip those
+            for (path, &topic) in &topics_database.topic_of {
+                if topics_database.embeddings.contains_key(path) {
+                    if let Some(count) = counts.get_mut(topic) {
+                        *count += 1;
+                    }
+                }
+            }
+            let topics: Vec<_> = topics_database
+                .topics
+                .iter()
+                .zip(counts)
+                .enumerate()
+                .map(|(id, (topic, count))| {
+                    serde_json::json!({ "id": id, "label": topic.label, "count": count })
+                })
+                .collect();
+
+            api::json_response(&serde_json::json!({
+                "version": api::API_VERSION,
+                "total": topics.len(),
+                "topics": topics,
+            }))
+        }),
+    );
+
+    let topic_images_collections = collections.clone();
+    httpd.route(
+        "/api/topicImages",
+        api::handler(move |_, _, params, _| {
+            let started = std::time::Instant::now();
+            let (name, database) = topic_images_collections.one(&params)?;
+            let database = &*database.read().unwrap();
+            let id: usize = api::required(&params, "id")?
+                .parse()
+                .map_err(|_| ApiError::new(400, "invalid parameter 'id'"))?;
+            let topic = database
+                .topics
+                .get(id)
+                .ok_or_else(|| ApiError::new(404, "unknown topic"))?;
+            let mut items: Vec<_> = database
+                .topic_of
+                .iter()
+                .filter(|(_, &t)| t == id)
+                .filter_map(|(path, _)| {
+                    let embedding = database.embeddings.get(path)?;
+                    Some(Hit {
+                        collection: name,
+                        database,
+                        path,
+                        score: dot_product(embedding, &topic.centroid),
+                    })
+                })
+                .collect();
+            sort_hits(&mut items);
+            let (items, total) = ResultFilter::from_params(&params)?.apply(items);
+            api::results_response(&items, total, started)
+        }),
+    );
+
+    let index_collections = collections.clone();
+    let index_jobs = jobs.clone();
+    httpd.route(
+        "/api/index/add",
+        api::handler(move |_, headers, _, body| {
+            api_index_add(
+                &index_collections,
+                &index_roots,
+                &vision_model,
+                &filter,
+                &index_jobs,
+                &headers,
+                &body,
+            )
+        }),
+    );
+
+    let check_collections = collections.clone();
+    let check_jobs = jobs.clone();
+    httpd.route(
+        "/api/index/check",
+        api::handler(move |_, headers, _, body| {
+            api_index_check(&check_collections, &check_jobs, &headers, &body)
+        }),
+    );
+
+    let folder_collections = collections.clone();
+    httpd.route(
+        "/api/openFolder",
+        api::handler(move |_, headers, _, body| {
+            api_open_folder(&folder_collections, &headers, &body)
+        }),
+    );
+
+    httpd.route(
+        "/api/jobs",
+        api::handler(move |_, _, params, _| api_jobs(&jobs, &params)),
+    );
+
+    let listed_collections = collections.clone();
+    httpd.route(
+        "/api/collections",
+        api::handler(move |_, _, _, _| {
+            let list: Vec<_> = listed_collections
+                .0
+                .iter()
+                .map(|(name, database)| {
+                    serde_json::json!({ "name": name, "count": database.read().unwrap().len() })
+                })
+                .collect();
+            api::json_response(&serde_json::json!({
+                "version": api::API_VERSION,
+                "collections": list,
+            }))
+        }),
+    );
+
+    let history_userdata = userdata.clone();
+    httpd.route(
+        "/api/history/clear",
+        api::handler(move |_, headers, _, body| {
+            api::json_body::<serde::de::IgnoredAny>(&headers, &body)?;
+            update_userdata(&history_userdata, &headers, |store| store.history.clear())?;
+            api::json_response(&serde_json::json!({ "version": api::API_VERSION }))
+        }),
+    );
+    let history_userdata = userdata.clone();
+    httpd.route(
+        "/api/history",
+        api::handler(move |_, headers, _, _| {
+            let history = read_userdata(&history_userdata, &headers, |store| store.history.clone());
+            api::json_response(&serde_json::json!({
+                "version": api::API_VERSION,
+                "history": history,
+            }))
+        }),
+    );
+
+    let saved_userdata = userdata.clone();
+    httpd.route(
+        "/api/saved/add",
+        api::handler(move |_, headers, _, body| {
+            api_saved_change(&saved_userdata, &headers, &body, false)
+        }),
+    );
+    let saved_userdata = userdata.clone();
+    httpd.route(
+        "/api/saved/delete",
+        api::handler(move |_, headers, _, body| {
+            api_saved_change(&saved_userdata, &headers, &body, true)
+        }),
+    );
+    let saved_userdata = userdata.clone();
+    httpd.route(
+        "/api/saved",
+        api::handler(move |_, headers, _, _| api_saved(&saved_userdata, &headers)),
+    );
+
+    let (favourites_collections, favourites_userdata) = (collections.clone(), userdata.clone());
+    httpd.route(
+        "/api/favourites/add",
+        api::handler(move |_, headers, _, body| {
+            api_favourites_change(
+                &favourites_collections,
+                &favourites_userdata,
+                &headers,
+                &body,
+                false,
+            )
+        }),
+    );
+    let (favourites_collections, favourites_userdata) = (collections.clone(), userdata.clone());
+    httpd.route(
+        "/api/favourites/remove",
+        api::handler(move |_, headers, _, body| {
+            api_favourites_change(
+                &favourites_collections,
+                &favourites_userdata,
+                &headers,
+                &body,
+                true,
+            )
+        }),
+    );
+    httpd.route(
+        "/api/favourites",
+        api::handler(move |_, headers, params, _| {
+            api_favourites(&collections, &userdata, &headers, &params)
+        }),
+    );
+
+    httpd.route_static(
+        "/api/openapi.json",
+        "application/json",
+        api::OPENAPI.to_string(),
+    );
+    // anything else under /api/ must not fall through to the page below
+    httpd.route(
+        "/api/",
+        api::handler(|_, _, _, _| Err(ApiError::new(404, "unknown endpoint"))),
+    );
+
+    httpd.route_static("", "text/html", include_str!("index.html").to_string());
+
+    let Err(e) = match (tls_cert, tls_key) {
+        (None, None) => {
+            log::info!("starting server at http://{}", addr);
+            httpd.serve(addr)
+        }
+        #[cfg(feature = "tls")]
+        (Some(cert), Some(key)) => {
+            let config = tls::load_config(&cert, &key)
+                .map_err(|e| Error::Config(format!("failed to load {} or {}: {}", cert, key, e)))?;
+            log::info!("starting server at https://{}", addr);
+            httpd.serve_with(addr, |stream| tls::accept(&config, stream))
+        }
+        #[cfg(not(feature = "tls"))]
+        (Some(_), Some(_)) => return Err("tls support not enabled".into()),
+        _ => return Err("--tls-cert and --tls-key must be given together".into()),
+    };
+    Err(e.to_string().into())
+}
+
+/// Exits with status 1 after logging the error, instead of printing its
+/// debug form.
+fn main() -> std::process::ExitCode {
+    match run() {
+        Ok(()) => std::process::ExitCode::SUCCESS,
+        Err(e) => {
+            log::error!("{}", e);
+            std::process::ExitCode::FAILURE
+        }
+    }
+}
+
+fn run() -> error::Result<()> {
+    let cli = cli::Cli::parse();
+    logging::init(cli.log_level, cli.log_format);
+    if let Some(threads) = cli.threads {
+        // candle reads this for every matrix multiplication
+        std::env::set_var("RAYON_NUM_THREADS", threads.to_string());
+        THREADS.set(threads.get()).ok();
+    }
+    let config = config::Config::load(cli.config.as_deref())?;
+    let model_dir = config.model_dir(cli.model_dir);
+    let model_dir = model_dir.as_path();
+    let tokenizer = config.tokenizer(cli.tokenizer, model_dir);
+    let tokenizer = tokenizer.as_path();
+    let collection = config.collection(cli.collection.as_deref(), cli.database.clone())?;
+    let load_database = || load_database(&collection.database);
+
+    match cli.command {
+        Command::Add {
+            paths,
+            thumbnails,
+            retry,
+            failures: report_path,
+            follow_symlinks,
+            one_file_system,
+        } => {
+            let mut database = load_database()?;
+            let images = match retry.as_deref() {
+                Some(report) => scan::Scan::list(
+                    report::read_report(report)?
+                        .into_iter()
+                        .map(|failure| failure.path)
+                        .collect(),
+                ),
+                None => {
+                    let mut filter = config.scan_filter()?;
+                    filter.follow_symlinks |= follow_symlinks;
+                    filter.one_file_system |= one_file_system;
+                    let paths = add_roots(&mut database, &collection.paths_or_roots(paths)?)?;
+                    scan_images(&paths, &filter)?
+                }
+            };
+            let model = load_vision_model(model_dir)?;
+            let failures = command_add_images(&mut database, images, &model, thumbnails);
+            save_database(&database)?;
+            // an earlier report is overwritten, so it never lists images
+            // that have since been indexed
+            let report_path = report_path.unwrap_or_else(|| {
+                collection
+                    .database
+                    .with_extension(report::FAILURES_EXTENSION)
+            });
+            if !failures.is_empty() || report_path.exists() {
+                report::write_report(&report_path, &failures)?;
+            }
+            if !failures.is_empty() {
+                log::warn!(
+                    "{} images failed, listed in {}; run `imgfind add --retry {}` to try them again",
+                    failures.len(),
+                    report_path.display(),
+                    report_path.display()
+                );
+            }
+        }
+        Command::Find {
+            text,
+            all,
+            filter,
+            format,
+        } => {
+            let collections = if all {
+                config.collections(cli.database)?
+            } else {
+                vec![collection]
+            };
+            let loaded: Vec<_> = collections
+                .iter()
+                .map(|collection| database::load_database(&collection.database))
+                .collect::<error::Result<_>>()?;
+            let databases: Vec<_> = collections
+                .iter()
+                .zip(loaded.iter())
+                .map(|(collection, database)| (collection.name.as_str(), database))
+                .collect();
+            let (model, tokenizer) = load_text_model(model_dir, tokenizer)?;
+            let filter = ResultFilter {
+                min_score: filter.min_score,
+                auto_cutoff: filter.auto_cutoff,
+                tags: filter
+                    .tag
+                    .iter()
+                    .flat_map(|tags| tag::parse_tags(tags))
+                    .collect(),
+                ..Default::default()
+            };
+            command_find_image(&databases, &model, &tokenizer, &text, &filter, format)?;
+        }
+        Command::Dupes { threshold, phash } => {
+            command_find_dupes(&load_database()?, threshold, phash);
+        }
+        Command::Cluster { k, vocab } => {
+            let vocabulary: Vec<String> = match vocab {
+                Some(file) => std::fs::read_to_string(file)?
+                    .lines()
+                    .map(str::trim)
+                    .filter(|line| !line.is_empty())
+                    .map(str::to_string)
+                    .collect(),
+                None => cluster::DEFAULT_VOCABULARY
+                    .iter()
+                    .map(|word| word.to_string())
+                    .collect(),
+            };
+            if vocabulary.is_empty() {
+                return Err("vocabulary is empty".into());
+            }
+            let mut database = load_database()?;
+            let (model, tokenizer) = load_text_model(model_dir, tokenizer)?;
+            cluster::cluster_database(&mut database, &model, &tokenizer, k, &vocabulary)?;
+            let mut counts = vec![0; database.topics.len()];
+            for &topic in database.topic_of.values() {
+                if let Some(count) = counts.get_mut(topic) {
+                    *count += 1;
+                }
+            }
+            for (i, (topic, count)) in database.topics.iter().zip(counts).enumerate() {
+                println!("{:>3} {:>7}  {}", i, count, topic.label);
+            }
+            save_database(&database)?;
+        }
+        Command::Tag { tags, threshold } => {
+            let labels = tag::parse_tags(&tags);
+            if labels.is_empty() {
+                return Err("no tags given".into());
+            }
+            let mut database = load_database()?;
+            let (model, tokenizer) = load_text_model(model_dir, tokenizer)?;
+            let logit_scale = load_logit_scale(model_dir)?;
+            tag::tag_database(
+                &mut database,
+                &model,
+                &tokenizer,
+                logit_scale,
+                &labels,
+                threshold,
+            )?;
+            for label in labels.iter() {
+                let count = database
+                    .tags
+                    .values()
+                    .filter(|tags| tags.iter().any(|(t, _)| t == label))
+                    .count();
+                println!("{:>7}  {}", count, label);
+            }
+            save_database(&database)?;
+        }
+        Command::Watch { paths, thumbnails } => {
+            let mut database = load_database()?;
+            let paths = add_roots(&mut database, &collection.paths_or_roots(paths)?)?;
+            let thumbnails = thumbnails.then(|| thumbnail::cache_dir(database.path()));
+            let watch = watch::Watch::new(&paths, config.scan_filter()?, thumbnails)?;
+            let model = load_vision_model(model_dir)?;
+            log::info!("watching {}", paths.join(", "));
+            watch.run(&RwLock::new(database), &model);
+        }
+        Command::Check { format } => {
+            let mut database = load_database()?;
+            command_check(&mut database, &collection.name, format)?;
+            save_database(&database)?;
+        }
+        Command::List { all, format } => {
+            let collections = if all {
+                config.collections(cli.database)?
+            } else {
+                vec![collection]
+            };
+            let loaded: Vec<_> = collections
+                .iter()
+                .map(|collection| database::load_database(&collection.database))
+                .collect::<error::Result<_>>()?;
+            let rows: Vec<_> = collections
+                .iter()
+                .zip(loaded.iter())
+                .flat_map(|(collection, database)| {
+                    database
+                        .embeddings
+                        .keys()
+                        .map(|path| image_entry(&collection.name, database, path))
+                })
+                .collect();
+            output::print_rows(format, &rows)?;
+        }
+        Command::Stats { format } => {
+            let collections = config.collections(cli.database)?;
+            let loaded: Vec<_> = collections
+                .iter()
+                .map(|collection| database::load_database(&collection.database))
+                .collect::<error::Result<_>>()?;
+            let rows: Vec<_> = collections
+                .iter()
+                .zip(loaded.iter())
+                .map(|(collection, database)| output::CollectionStats {
+                    collection: &collection.name,
+                    images: database.len(),
+                    tagged: database
+                        .tags
+                        .values()
+                        .filter(|tags| !tags.is_empty())
+                        .count(),
+                    topics: database.topics.len(),
+                    database: collection.database.to_string_lossy().to_string(),
+                    database_size: std::fs::metadata(&collection.database)
+                        .ok()
+                        .map(|m| m.len()),
+                })
+                .collect();
+            output::print_rows(format, &rows)?;
+        }
+        Command::Remap { root, location } => {
+            let mut database = load_database()?;
+            let (Some(root), Some(location)) = (root, location) else {
+                for root in database.roots.iter() {
+                    let count = database
+                        .embeddings
+                        .keys()
+                        .filter(|path| root.contains(path))
+                        .count();
+                    println!("{:<15} {:>7}  {}", root.name, count, root.location);
+                }
+                return Ok(());
+            };
+            if !location.is_dir() {
+                return Err(format!("{} is not a directory", location.display()).into());
+            }
+            let absolute = std::path::absolute(&location).map_err(|e| Error::io(&location, e))?;
+            let key = database::path_key(&absolute)
+                .ok_or_else(|| format!("{} is not valid unicode", location.display()))?;
+            let (old, new, moved) = database.remap(&root, &key)?;
+            save_database(&database)?;
+            // favourites are kept by path next to the default collection
+            let default = &config.collections(cli.database)?[0];
+            let mut userdata = userdata::load_userdata(&default.database)?;
+            if userdata.rebase(&collection.name, &old, &new) {
+                userdata::save_userdata(&userdata)?;
+            }
+            log::info!("moved {} images from {} to {}", moved, old.location, key);
+        }
+        Command::Export { output, format } => {
+            let format = format
+                .or_else(|| export::ExportFormat::of(&output))
+                .ok_or_else(|| format!("cannot tell the format of {}", output.display()))?;
+            let database = load_database()?;
+            export::export(&database, &output, format)?;
+            log::info!("exported {} images to {}", database.len(), output.display());
+        }
+        Command::Import {
+            input,
+            format,
+            model,
+            overwrite,
+        } => {
+            let format = format
+                .or_else(|| export::ExportFormat::of(&input))
+                .ok_or_else(|| format!("cannot tell the format of {}", input.display()))?;
+            let mut embeddings = export::import(&input, format)?;
+            embeddings.model = embeddings.model.or(model);
+            let mut database = load_database()?;
+            let (added, skipped) = export::merge(&mut database, embeddings, overwrite)?;
+            save_database(&database)?;
+            log::info!(
+                "imported {} images, skipped {} already indexed",
+                added,
+                skipped
+            );
+        }
+        Command::HashPassword => {
+            // read from stdin so the password stays out of shell history
+            let mut password = String::new();
+            std::io::stdin().read_line(&mut password)?;
+            let password = password.trim_end_matches(['\r', '\n']);
+            println!(
+                "{}",
+                auth::hash_password(password).map_err(|e| e.to_string())?
+            );
+        }
+        Command::Collections => {
+            for collection in config.collections(cli.database)? {
+                let count = match std::fs::metadata(&collection.database) {
+                    Ok(_) => database::load_database(&collection.database)?
+                        .len()
+                        .to_string(),
+                    Err(_) => "-".to_string(),
+                };
+                println!(
+                    "{:<15} {:>7}  {}",
+                    collection.name,
+                    count,
+                    collection.database.display()
+                );
+                for root in collection.roots.iter() {
+                    println!("{:<15} {:>7}  {}", "", "", root);
+                }
+            }
+        }
+        Command::Serve(args) => {
+            let collections = config.collections(cli.database)?;
+            command_serve(&config, &collections, model_dir, tokenizer, args)?
+        }
+        Command::Completions { shell } => {
+            let mut command = cli::Cli::command();
+            let name = command.get_name().to_string();
+            clap_complete::generate(shell, &mut command, name, &mut std::io::stdout());
+        }
     }
-    println!("usage: clip add <path> | clip find <text> | clip serve <port>");
     Ok(())
 }
 
@@ -315,7 +2128,64 @@ mod tests {
     use super::*;
     #[test]
     fn test_get_images() {
-        let images = get_images(".");
+        let images: Vec<_> = scan_images(&[".".to_string()], &scan::Filter::default())
+            .unwrap()
+            .collect();
         println!("{:?}", images);
     }
+
+    #[test]
+    fn test_relevance_cutoff() {
+        assert_eq!(
+            relevance_cutoff(&[0.34, 0.33, 0.32, 0.22, 0.215, 0.21, 0.205]),
+            3
+        );
+        assert_eq!(relevance_cutoff(&[0.30, 0.295, 0.29, 0.285, 0.28]), 5);
+        assert_eq!(relevance_cutoff(&[0.3, 0.1]), 2);
+    }
+
+    #[test]
+    fn test_result_filter_paging() {
+        let paths: Vec<String> = (0..10).map(|i| format!("{}.jpg", i)).collect();
+        let database = Database::default();
+        let scores: Vec<_> = paths
+            .iter()
+            .enumerate()
+            .map(|(i, path)| Hit {
+                collection: config::DEFAULT_COLLECTION,
+                database: &database,
+                path,
+                score: 1.0 - i as f32 * 0.1,
+            })
+            .collect();
+        let filter = ResultFilter {
+            offset: 4,
+            limit: 3,
+            min_score: Some(0.25),
+            ..Default::default()
+        };
+        let (page, total) = filter.apply(scores);
+        assert_eq!(total, 8);
+        let page: Vec<&str> = page.iter().map(|hit| hit.path.as_str()).collect();
+        assert_eq!(page, ["4.jpg", "5.jpg", "6.jpg"]);
+    }
+
+    #[test]
+    fn test_rank_not_finite() {
+        let mut database = Database::default();
+        database.insert("a.jpg".to_string(), vec![1.0, 0.0]);
+        database.insert("b.jpg".to_string(), vec![f32::NAN, 0.0]);
+        database.insert("c.jpg".to_string(), vec![0.5, 0.5]);
+        let ranked = rank(&[(config::DEFAULT_COLLECTION, &database)], &[1.0, 0.0]);
+        let paths: Vec<&str> = ranked.iter().map(|hit| hit.path.as_str()).collect();
+        assert_eq!(paths, ["a.jpg", "c.jpg"]);
+    }
+
+    #[test]
+    fn test_dot_product() {
+        let x: Vec<f32> = (0..515).map(|i| (i as f32 * 0.37).sin()).collect();
+        let y: Vec<f32> = (0..515).map(|i| (i as f32 * 0.11).cos()).collect();
+        let expected: f32 = x.iter().zip(&y).map(|(x, y)| x * y).sum();
+        assert!((dot_product(&x, &y) - expected).abs() < 1e-4);
+    }
 }
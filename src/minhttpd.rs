@@ -0,0 +1,580 @@
+//! A minimal threaded HTTP/1.1 server, vendored from `xjbutil` 0.9 with a
+//! request guard, a body size limit, connection wrapping for TLS, streamed
+//! responses and percent-decoding of query parameters added.
+//!
+//! MIT License
+//!
+//! Copyright (c) 2021 Project-47
+//!
+//! Permission is hereby granted, free of charge, to any person obtaining a copy
+//! of this software and associated documentation files (the "Software"), to deal
+//! in the Software without restriction, including without limitation the rights
+//! to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+//! copies of the Software, and to permit persons to whom the Software is
+//! furnished to do so, subject to the following conditions:
+//!
+//! The above copyright notice and this permission notice shall be included in all
+//! copies or substantial portions of the Software.
+//!
+//! THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+//! IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+//! FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+//! AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+//! LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+//! OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+//! SOFTWARE.
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::error::Error;
+use std::io::{BufRead, BufReader, BufWriter, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering::SeqCst;
+use std::thread;
+
+pub type HttpUri = String;
+pub type HttpHeaders = HashMap<String, String>;
+pub type HttpParams = HashMap<String, String>;
+pub type HttpBody = Option<Vec<u8>>;
+/// A response body read while it is being sent, together with its exact length.
+pub type HttpPayloadStream = (Box<dyn Read + Send>, u64);
+
+pub struct HttpResponse {
+    pub code: u16,
+    pub headers: Vec<(String, String)>,
+    pub payload: Option<Vec<u8>>,
+    pub payload_stream: Option<HttpPayloadStream>,
+}
+
+impl HttpResponse {
+    pub fn new(code: u16, headers: Vec<(String, String)>, payload: Option<String>) -> Self {
+        Self {
+            code,
+            headers,
+            payload: payload.map(String::into_bytes),
+            payload_stream: None,
+        }
+    }
+
+    pub fn new_raw(code: u16, headers: Vec<(String, String)>, payload: Option<Vec<u8>>) -> Self {
+        Self {
+            code,
+            headers,
+            payload,
+            payload_stream: None,
+        }
+    }
+
+    pub fn builder() -> HttpResponseBuilder {
+        HttpResponseBuilder {
+            code: 200,
+            headers: Vec::new(),
+            payload: None,
+            payload_stream: None,
+        }
+    }
+
+    pub fn add_header(&mut self, header: &str, value: &str) {
+        self.headers.push((header.to_string(), value.to_string()));
+    }
+
+    pub fn has_header(&self, header: &str) -> bool {
+        self.headers
+            .iter()
+            .any(|(h, _)| h.eq_ignore_ascii_case(header))
+    }
+}
+
+pub struct HttpResponseBuilder {
+    code: u16,
+    headers: Vec<(String, String)>,
+    payload: Option<Vec<u8>>,
+    payload_stream: Option<HttpPayloadStream>,
+}
+
+impl HttpResponseBuilder {
+    pub fn set_code(mut self, code: u16) -> Self {
+        self.code = code;
+        self
+    }
+
+    pub fn add_header(mut self, key: impl Into<String>, value: impl Into<String>) -> Self {
+        self.headers.push((key.into(), value.into()));
+        self
+    }
+
+    pub fn set_payload(mut self, payload: impl Into<String>) -> Self {
+        self.payload = Some(payload.into().into_bytes());
+        self
+    }
+
+    pub fn set_payload_raw(mut self, payload: impl Into<Vec<u8>>) -> Self {
+        self.payload = Some(payload.into());
+        self
+    }
+
+    /// Streams `len` bytes from `reader` as the body, instead of a buffered payload.
+    pub fn set_payload_stream(mut self, reader: impl Read + Send + 'static, len: u64) -> Self {
+        self.payload_stream = Some((Box::new(reader), len));
+        self
+    }
+
+    pub fn build(self) -> HttpResponse {
+        let mut response = HttpResponse::new_raw(self.code, self.headers, self.payload);
+        response.payload_stream = self.payload_stream;
+        response
+    }
+}
+
+pub fn http_code_describe(code: u16) -> &'static str {
+    match code {
+        100 => "Continue",
+        101 => "Switching Protocols",
+        200 => "OK",
+        201 => "Created",
+        202 => "Accepted",
+        203 => "Non-Authoritative Information",
+        204 => "No Content",
+        205 => "Reset Content",
+        206 => "Partial Content",
+        300 => "Multiple Choices",
+        301 => "Moved Permanently",
+        302 => "Found",
+        303 => "See Other",
+        304 => "Not Modified",
+        305 => "Use Proxy",
+        307 => "Temporary Redirect",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        402 => "Payment Required",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        406 => "Not Acceptable",
+        407 => "Proxy Authentication Required",
+        408 => "Request Timeout",
+        409 => "Conflict",
+        410 => "Gone",
+        411 => "Length Required",
+        412 => "Precondition Failed",
+        413 => "Request Entity Too Large",
+        414 => "Request-URI Too Long",
+        415 => "Unsupported Media Type",
+        416 => "Requested Range Not Satisfiable",
+        417 => "Expectation Failed",
+        500 => "Internal Server Error",
+        501 => "Not Implemented",
+        502 => "Bad Gateway",
+        503 => "Service Unavailable",
+        504 => "Gateway Timeout",
+        505 => "HTTP Version Not Supported",
+        _ => "Unknown",
+    }
+}
+
+const HTTP_404_STRING: &str = r#"<html lang="en">
+    <meta charset="utf-8">
+    <body style="text-align: center">
+        <h1>404 Not Found</h1>
+        <hr />
+        <div>xjbutil/0.9 rhttpd</div>
+    </body>
+</html>
+"#;
+
+pub type HttpHandler = Box<
+    dyn Fn(HttpUri, HttpHeaders, HttpParams, HttpBody) -> Result<HttpResponse, Box<dyn Error>>
+        + Send
+        + Sync
+        + 'static,
+>;
+
+/// Inspects every request before it is routed. Returning `Some` answers the
+/// request with that response instead of calling the handler.
+pub type HttpGuard = Box<
+    dyn Fn(&HttpUri, &HttpHeaders, &HttpParams) -> Option<HttpResponse> + Send + Sync + 'static,
+>;
+
+/// Builds the response to a request whose body is larger than allowed.
+pub type HttpTooLarge = Box<dyn Fn(&HttpUri) -> HttpResponse + Send + Sync + 'static>;
+
+pub struct MinHttpd {
+    handlers: Vec<(HttpUri, HttpHandler)>,
+    guard: Option<HttpGuard>,
+    max_body_size: Option<(usize, HttpTooLarge)>,
+    request_counter: AtomicU64,
+}
+
+impl MinHttpd {
+    pub fn new() -> Self {
+        Self {
+            handlers: Vec::new(),
+            guard: None,
+            max_body_size: None,
+            request_counter: AtomicU64::new(0),
+        }
+    }
+
+    pub fn route(&mut self, uri: &str, handler: HttpHandler) {
+        self.handlers.push((uri.to_string(), handler));
+    }
+
+    pub fn route_static(&mut self, uri: &str, content_type: &str, content: String) {
+        let content_type: String = content_type.to_string();
+        self.handlers.push((
+            uri.to_string(),
+            Box::new(move |_, _, _, _| {
+                Ok(HttpResponse::new(
+                    200,
+                    vec![("Content-Type".to_string(), content_type.clone())],
+                    Some(content.clone()),
+                ))
+            }),
+        ));
+    }
+
+    pub fn set_guard(&mut self, guard: HttpGuard) {
+        self.guard = Some(guard);
+    }
+
+    /// Requests with a larger `Content-Length` are answered with the response
+    /// of `too_large`, which should be a 413, without reading the body.
+    pub fn set_max_body_size(&mut self, max_body_size: usize, too_large: HttpTooLarge) {
+        self.max_body_size = Some((max_body_size, too_large));
+    }
+
+    pub fn serve(&self, addr: impl ToSocketAddrs) -> Result<Infallible, Box<dyn Error>> {
+        self.serve_with(addr, Ok)
+    }
+
+    /// Like `serve`, but every accepted connection is first passed through
+    /// `wrap`, e.g. to layer TLS over it. `wrap` runs on the accepting thread,
+    /// so it should not block; handshakes belong in the returned stream.
+    pub fn serve_with<S, F>(
+        &self,
+        addr: impl ToSocketAddrs,
+        wrap: F,
+    ) -> Result<Infallible, Box<dyn Error>>
+    where
+        S: Read + Write + Send,
+        F: Fn(TcpStream) -> std::io::Result<S>,
+    {
+        let tcp_listener: TcpListener = TcpListener::bind(addr)?;
+        thread::scope(|scope| loop {
+            let (stream, addr): (TcpStream, SocketAddr) = tcp_listener.accept()?;
+            let request_id: u64 = self.request_counter.fetch_add(1, SeqCst);
+            log::debug!(
+                "[MIN-HTTPD/{}] Accepted connection from: {}",
+                request_id,
+                addr
+            );
+
+            match wrap(stream) {
+                Ok(stream) => {
+                    scope.spawn(move || {
+                        let remote_addr = addr.ip().to_string();
+                        if let Err(e) = self.handle_connection(stream, remote_addr, request_id) {
+                            log::error!(
+                                "[MIN-HTTPD/{}] Error handling connection: {}",
+                                request_id,
+                                e
+                            );
+                        }
+                    });
+                }
+                Err(e) => log::error!(
+                    "[MIN-HTTPD/{}] Error wrapping connection: {}",
+                    request_id,
+                    e
+                ),
+            }
+        })
+    }
+
+    fn handle_connection<S: Read + Write>(
+        &self,
+        stream: S,
+        remote_addr: String,
+        request_id: u64,
+    ) -> Result<(), Box<dyn Error>> {
+        let mut reader: BufReader<S> = BufReader::new(stream);
+
+        let mut line: String = String::new();
+        reader.read_line(&mut line)?;
+
+        let parts: Vec<&str> = line.split_whitespace().collect::<Vec<_>>();
+        if parts.len() != 3 {
+            log::error!("[MIN-HTTPD/{}] Invalid HTTP request: {}", request_id, line);
+            return Ok(());
+        }
+        let method: String = parts[0].to_lowercase();
+        let version: String = parts[2].to_lowercase();
+
+        if method != "get" && method != "post" {
+            log::error!(
+                "[MIN-HTTPD/{}] Invalid HTTP method: {}",
+                request_id,
+                parts[0]
+            );
+            return Ok(());
+        }
+        if version != "http/1.1" && version != "http/1.0" {
+            log::error!(
+                "[MIN-HTTPD/{}] Invalid HTTP version: {}",
+                request_id,
+                parts[2]
+            );
+            return Ok(());
+        }
+
+        let uri: String = parts[1].to_string();
+        let uri_parts: Vec<&str> = uri.split("?").collect::<Vec<_>>();
+        let mut uri: String = uri_parts[0].to_string();
+
+        if uri.ends_with("/") {
+            uri.pop();
+        }
+
+        let params: HashMap<String, String> = if uri_parts.len() > 1 {
+            let mut params: HashMap<String, String> = HashMap::new();
+            for param in uri_parts[1].split("&") {
+                let (name, value) = param.split_once('=').unwrap_or((param, ""));
+                params.insert(percent_decode(name), percent_decode(value));
+            }
+            params
+        } else {
+            HashMap::new()
+        };
+
+        let mut headers: HashMap<String, String> = HashMap::new();
+        loop {
+            line.clear();
+            reader.read_line(&mut line)?;
+            if line.trim().is_empty() {
+                break;
+            }
+            let parts: Vec<&str> = line.trim().splitn(2, ": ").collect::<Vec<_>>();
+            if parts.len() != 2 {
+                log::error!("[MIN-HTTPD/{}] Invalid HTTP header: {}", request_id, line);
+                return Ok(());
+            }
+            // header values are kept verbatim, credentials and ETags are case-sensitive
+            headers.insert(parts[0].to_lowercase().to_string(), parts[1].to_string());
+        }
+        headers.insert("X-47-Remote-Addr".to_string(), remote_addr);
+
+        // the guard runs first, so unauthorized clients cannot make us read a body
+        let mut guarded: Option<HttpResponse> = self
+            .guard
+            .as_ref()
+            .and_then(|guard| guard(&uri, &headers, &params));
+        let body: Option<Vec<u8>> = if guarded.is_none() && headers.contains_key("content-length") {
+            let content_length: Option<usize> = headers["content-length"].parse().ok();
+            let too_large = self
+                .max_body_size
+                .as_ref()
+                .filter(|(max, _)| content_length.is_some_and(|len| len > *max));
+            if let Some((_, too_large)) = too_large {
+                guarded = Some(too_large(&uri));
+                None
+            } else if let Some(content_length) = content_length {
+                let mut buffer: Vec<u8> = vec![0; content_length];
+                reader.read_exact(&mut buffer)?;
+                Some(buffer)
+            } else {
+                guarded = Some(plain_text(400, "invalid Content-Length".to_string()));
+                None
+            }
+        } else {
+            None
+        };
+
+        let mut writer: BufWriter<&mut S> = BufWriter::new(reader.get_mut());
+
+        let handler: Option<&(HttpUri, HttpHandler)> =
+            self.handlers.iter().find(|h| uri.starts_with(&h.0));
+        if guarded.is_some() || handler.is_some() {
+            let result: Result<HttpResponse, Box<dyn Error>> = match (guarded, handler) {
+                (Some(response), _) => Ok(response),
+                (None, Some(handler)) => (handler.1)(uri.to_string(), headers, params, body),
+                (None, None) => unreachable!(),
+            };
+            let mut response: HttpResponse = match result {
+                Ok(result) => result,
+                Err(e) => {
+                    log::error!("[MIN-HTTPD/{}] Error handling request: {}", request_id, e);
+                    // plain text, so the error cannot inject markup
+                    plain_text(500, e.to_string())
+                }
+            };
+
+            if response.has_header("Content-Length") {
+                log::error!(
+                    "[MIN-HTTPD/{}] Setting `Content-Length` is not allowed",
+                    request_id
+                );
+                return Ok(());
+            }
+
+            if response.has_header("Connection") {
+                log::error!(
+                    "[MIN-HTTPD/{}] Setting `Connection` is not allowed",
+                    request_id
+                );
+                return Ok(());
+            }
+
+            response.add_header("Connection", "close");
+            if !response.has_header("Server") {
+                response.add_header("Server", "xjbutil/0.9 rhttpd");
+            }
+
+            write!(
+                writer,
+                "HTTP/1.1 {} {}\r\n",
+                response.code,
+                http_code_describe(response.code)
+            )?;
+            for (key /*: String*/, value /*: String*/) in response.headers {
+                write!(writer, "{}: {}\r\n", key, value)?;
+            }
+            if let Some((reader, len)) = response.payload_stream {
+                write!(writer, "Content-Length: {}\r\n", len)?;
+                write!(writer, "\r\n")?;
+                std::io::copy(&mut reader.take(len), &mut writer)?;
+            } else if let Some(payload) = response.payload {
+                write!(writer, "Content-Length: {}\r\n", payload.len())?;
+                write!(writer, "\r\n")?;
+                writer.write_all(&payload)?;
+            } else {
+                write!(writer, "\r\n")?;
+            }
+        } else {
+            log::warn!("[MIN-HTTPD/{}] No handler for URI: {}", request_id, uri);
+
+            write!(writer, "HTTP/1.1 404 Not Found\r\n")?;
+            write!(writer, "Connection: close\r\n")?;
+            write!(writer, "Content-Type: text/html\r\n")?;
+            write!(writer, "Content-Length: {}\r\n", HTTP_404_STRING.len())?;
+            write!(writer, "\r\n")?;
+            writer.write_all(HTTP_404_STRING.as_bytes())?;
+        };
+        writer.flush()?;
+
+        Ok(())
+    }
+}
+
+fn plain_text(code: u16, text: String) -> HttpResponse {
+    HttpResponse::new(
+        code,
+        vec![("Content-Type".to_string(), "text/plain".to_string())],
+        Some(text),
+    )
+}
+
+impl Default for MinHttpd {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Decodes a `application/x-www-form-urlencoded` query component. Malformed
+/// escapes are kept as they are.
+fn percent_decode(s: &str) -> String {
+    let bytes: &[u8] = s.as_bytes();
+    let mut decoded: Vec<u8> = Vec::with_capacity(bytes.len());
+    let mut i: usize = 0;
+    while i < bytes.len() {
+        let escaped: Option<u8> = if bytes[i] == b'%' {
+            bytes
+                .get(i + 1..i + 3)
+                .filter(|hex| hex.iter().all(u8::is_ascii_hexdigit))
+                .and_then(|hex| std::str::from_utf8(hex).ok())
+                .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+        } else {
+            None
+        };
+        match (bytes[i], escaped) {
+            (_, Some(byte)) => {
+                decoded.push(byte);
+                i += 3;
+            }
+            (b'+', None) => {
+                decoded.push(b' ');
+                i += 1;
+            }
+            (byte, None) => {
+                decoded.push(byte);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Stream {
+        input: std::io::Cursor<Vec<u8>>,
+        output: Vec<u8>,
+    }
+
+    impl Read for Stream {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            self.input.read(buf)
+        }
+    }
+
+    impl Write for Stream {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.output.write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn request(httpd: &MinHttpd, request: &str) -> String {
+        let mut stream = Stream {
+            input: std::io::Cursor::new(request.as_bytes().to_vec()),
+            output: Vec::new(),
+        };
+        httpd
+            .handle_connection(&mut stream, String::new(), 0)
+            .unwrap();
+        String::from_utf8(stream.output).unwrap()
+    }
+
+    #[test]
+    fn test_requests() {
+        let mut httpd = MinHttpd::new();
+        httpd.route(
+            "/echo",
+            Box::new(|_, _, params, _| Ok(plain_text(200, params["q"].clone()))),
+        );
+        httpd.route("/fail", Box::new(|_, _, _, _| Err("<b>".into())));
+
+        let response = request(&httpd, "GET /echo?q=a=b%3D HTTP/1.1\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 200 "));
+        assert!(response.ends_with("\r\n\r\na=b="));
+        let response = request(
+            &httpd,
+            "POST /echo?q= HTTP/1.1\r\nContent-Length: x\r\n\r\n",
+        );
+        assert!(response.starts_with("HTTP/1.1 400 "));
+        let response = request(&httpd, "GET /fail HTTP/1.1\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 500 "));
+        assert!(response.contains("Content-Type: text/plain\r\n"));
+    }
+
+    #[test]
+    fn test_percent_decode() {
+        assert_eq!(percent_decode("a+b%20c%26d"), "a b c&d");
+        assert_eq!(percent_decode("%E7%8C%AB"), "猫");
+        assert_eq!(percent_decode("100%+%2"), "100% %2");
+    }
+}
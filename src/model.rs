@@ -12,6 +12,11 @@ use candle_nn as nn;
 use candle_nn::Module;
 use nn::{Conv2dConfig, Embedding};
 
+/// The model whose embeddings the index holds.
+pub const MODEL_NAME: &str = "openai/clip-vit-base-patch32";
+/// Length of its image and text embeddings.
+pub const EMBEDDING_DIM: usize = 512;
+
 #[derive(Debug, Clone, Copy)]
 pub enum Activation {
     QuickGelu,
@@ -327,7 +332,7 @@ impl ClipEncoder {
         let vs = vs.pp("layers");
         let mut layers: Vec<ClipEncoderLayer> = Vec::new();
         for index in 0..c.num_hidden_layers {
-            let layer = ClipEncoderLayer::new(vs.pp(&index.to_string()), c)?;
+            let layer = ClipEncoderLayer::new(vs.pp(index.to_string()), c)?;
             layers.push(layer)
         }
         Ok(ClipEncoder { layers })
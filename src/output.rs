@@ -0,0 +1,271 @@
+use clap::ValueEnum;
+use serde::Serialize;
+use std::io::{ErrorKind, Write};
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, ValueEnum)]
+pub enum Format {
+    /// Lines for reading
+    #[default]
+    Text,
+    /// One JSON array
+    Json,
+    /// One JSON object per line
+    Jsonl,
+    /// Comma separated values with a header line
+    Csv,
+    /// Tab separated values with a header line
+    Tsv,
+    /// Only the paths, each followed by a NUL byte, for `xargs -0`
+    Paths0,
+}
+
+pub trait Row: Serialize {
+    /// In the order of `fields`.
+    const COLUMNS: &'static [&'static str];
+    fn fields(&self) -> Vec<String>;
+    fn text(&self) -> String;
+    fn path(&self) -> Option<&str> {
+        None
+    }
+}
+
+/// As in RFC 4180.
+fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+fn tsv_field(field: &str) -> String {
+    field
+        .replace('\\', "\\\\")
+        .replace('\t', "\\t")
+        .replace('\n', "\\n")
+        .replace('\r', "\\r")
+}
+
+pub fn write_rows<R: Row>(out: &mut impl Write, format: Format, rows: &[R]) -> std::io::Result<()> {
+    let separated = |out: &mut dyn Write, fields: Vec<String>| {
+        let (separator, escape): (&str, fn(&str) -> String) = match format {
+            Format::Csv => (",", csv_field),
+            _ => ("\t", tsv_field),
+        };
+        let fields: Vec<String> = fields.iter().map(|f| escape(f)).collect();
+        writeln!(out, "{}", fields.join(separator))
+    };
+    match format {
+        Format::Text => {
+            for row in rows {
+                writeln!(out, "{}", row.text())?;
+            }
+        }
+        Format::Json => {
+            serde_json::to_writer_pretty(&mut *out, rows)?;
+            writeln!(out)?;
+        }
+        Format::Jsonl => {
+            for row in rows {
+                serde_json::to_writer(&mut *out, row)?;
+                writeln!(out)?;
+            }
+        }
+        Format::Csv | Format::Tsv => {
+            separated(out, R::COLUMNS.iter().map(|c| c.to_string()).collect())?;
+            for row in rows {
+                separated(out, row.fields())?;
+            }
+        }
+        Format::Paths0 => {
+            for row in rows {
+                let path = row.path().ok_or_else(|| {
+                    std::io::Error::new(ErrorKind::InvalidInput, "this output has no paths")
+                })?;
+                // the real bytes of the name, which a key only stands for
+                #[cfg(unix)]
+                {
+                    use std::os::unix::ffi::OsStrExt;
+                    out.write_all(crate::database::key_path(path).as_os_str().as_bytes())?;
+                }
+                #[cfg(not(unix))]
+                out.write_all(path.as_bytes())?;
+                out.write_all(b"\0")?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// A reader that stops early, such as `head`, is not an error.
+pub fn print_rows<R: Row>(format: Format, rows: &[R]) -> std::io::Result<()> {
+    let mut out = std::io::BufWriter::new(std::io::stdout().lock());
+    match write_rows(&mut out, format, rows).and_then(|_| out.flush()) {
+        Err(e) if e.kind() == ErrorKind::BrokenPipe => Ok(()),
+        result => result,
+    }
+}
+
+#[derive(Serialize)]
+pub struct FoundImage<'a> {
+    pub score: f32,
+    pub collection: &'a str,
+    pub path: &'a str,
+    /// Set when several collections were searched.
+    #[serde(skip)]
+    pub show_collection: bool,
+}
+
+impl Row for FoundImage<'_> {
+    const COLUMNS: &'static [&'static str] = &["score", "collection", "path"];
+
+    fn fields(&self) -> Vec<String> {
+        vec![
+            format!("{:.4}", self.score),
+            self.collection.to_string(),
+            self.path.to_string(),
+        ]
+    }
+
+    fn text(&self) -> String {
+        if self.show_collection {
+            format!("{:.4} [{}] {}", self.score, self.collection, self.path)
+        } else {
+            format!("{:.4} {}", self.score, self.path)
+        }
+    }
+
+    fn path(&self) -> Option<&str> {
+        Some(self.path)
+    }
+}
+
+#[derive(Serialize)]
+pub struct ImageEntry<'a> {
+    pub id: String,
+    pub collection: &'a str,
+    pub path: &'a str,
+    pub tags: Vec<&'a str>,
+}
+
+impl Row for ImageEntry<'_> {
+    const COLUMNS: &'static [&'static str] = &["id", "collection", "path", "tags"];
+
+    fn fields(&self) -> Vec<String> {
+        vec![
+            self.id.clone(),
+            self.collection.to_string(),
+            self.path.to_string(),
+            self.tags.join(","),
+        ]
+    }
+
+    fn text(&self) -> String {
+        self.path.to_string()
+    }
+
+    fn path(&self) -> Option<&str> {
+        Some(self.path)
+    }
+}
+
+#[derive(Serialize)]
+pub struct CollectionStats<'a> {
+    pub collection: &'a str,
+    pub images: usize,
+    pub tagged: usize,
+    pub topics: usize,
+    pub database: String,
+    pub database_size: Option<u64>,
+}
+
+impl Row for CollectionStats<'_> {
+    const COLUMNS: &'static [&'static str] = &[
+        "collection",
+        "images",
+        "tagged",
+        "topics",
+        "database",
+        "database_size",
+    ];
+
+    fn fields(&self) -> Vec<String> {
+        vec![
+            self.collection.to_string(),
+            self.images.to_string(),
+            self.tagged.to_string(),
+            self.topics.to_string(),
+            self.database.clone(),
+            self.database_size
+                .map_or_else(String::new, |s| s.to_string()),
+        ]
+    }
+
+    fn text(&self) -> String {
+        format!(
+            "{}: {} images, {} tagged, {} topics, {} in {}",
+            self.collection,
+            self.images,
+            self.tagged,
+            self.topics,
+            self.database_size
+                .map_or("?".to_string(), crate::format_size),
+            self.database
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_write_rows() {
+        let rows = [FoundImage {
+            score: 0.5,
+            collection: "default",
+            path: "a, \"b\".jpg",
+            show_collection: false,
+        }];
+        let write = |format| {
+            let mut out = Vec::new();
+            write_rows(&mut out, format, &rows).unwrap();
+            String::from_utf8(out).unwrap()
+        };
+        assert_eq!(write(Format::Text), "0.5000 a, \"b\".jpg\n");
+        assert_eq!(
+            write(Format::Csv),
+            "score,collection,path\n0.5000,default,\"a, \"\"b\"\".jpg\"\n"
+        );
+        assert_eq!(
+            write(Format::Jsonl),
+            "{\"score\":0.5,\"collection\":\"default\",\"path\":\"a, \\\"b\\\".jpg\"}\n"
+        );
+        assert_eq!(write(Format::Paths0), "a, \"b\".jpg\0");
+        #[cfg(unix)]
+        {
+            use std::os::unix::ffi::OsStrExt;
+            let name = std::ffi::OsStr::from_bytes(b"caf\xe9.jpg");
+            let key = crate::database::path_key(std::path::Path::new(name)).unwrap();
+            let rows = [FoundImage {
+                path: &key,
+                ..rows[0]
+            }];
+            let mut out = Vec::new();
+            write_rows(&mut out, Format::Paths0, &rows).unwrap();
+            assert_eq!(out, b"caf\xe9.jpg\0");
+        }
+
+        let stats = [CollectionStats {
+            collection: "default",
+            images: 1,
+            tagged: 0,
+            topics: 0,
+            database: "database.bin".to_string(),
+            database_size: None,
+        }];
+        let error = write_rows(&mut Vec::new(), Format::Paths0, &stats).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::InvalidInput);
+        assert_eq!(tsv_field("a\tb\\"), "a\\tb\\\\");
+    }
+}
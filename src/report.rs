@@ -0,0 +1,74 @@
+//! The report `add` writes of the images it failed on, so that only those
+//! need to be retried with `add --retry`.
+use crate::error::{Error, ErrorKind};
+use serde::{Deserialize, Serialize};
+use std::io::{BufRead, Write};
+use std::path::Path;
+
+/// Extension of the default report, which is kept next to the database.
+pub const FAILURES_EXTENSION: &str = "failures.jsonl";
+
+/// A line of the failure report.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct Failure {
+    pub path: String,
+    pub kind: ErrorKind,
+    pub error: String,
+}
+
+impl Failure {
+    pub fn new(path: &str, error: &Error) -> Self {
+        Self {
+            path: path.to_string(),
+            kind: error.kind(),
+            error: error.to_string(),
+        }
+    }
+}
+
+/// Writes the failures as one JSON object per line.
+pub fn write_report(path: &Path, failures: &[Failure]) -> std::io::Result<()> {
+    let mut file = std::io::BufWriter::new(std::fs::File::create(path)?);
+    for failure in failures {
+        serde_json::to_writer(&mut file, failure)?;
+        writeln!(file)?;
+    }
+    file.flush()
+}
+
+pub fn read_report(path: &Path) -> crate::error::Result<Vec<Failure>> {
+    let file = std::fs::File::open(path).map_err(|e| Error::io(path, e))?;
+    std::io::BufReader::new(file)
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| !line.as_ref().is_ok_and(|line| line.trim().is_empty()))
+        .map(|(i, line)| {
+            let line = line.map_err(|e| Error::io(path, e))?;
+            serde_json::from_str(&line).map_err(|e| {
+                Error::Config(format!(
+                    "invalid line {} of {}: {}",
+                    i + 1,
+                    path.display(),
+                    e
+                ))
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_failure_report() {
+        let missing = std::io::Error::from(std::io::ErrorKind::NotFound);
+        let failures = vec![Failure::new("a.jpg", &Error::io("a.jpg", missing))];
+        assert_eq!(failures[0].kind, ErrorKind::Io);
+        let path =
+            std::env::temp_dir().join(format!("imgfind-report-{}.jsonl", std::process::id()));
+        write_report(&path, &failures).unwrap();
+        assert_eq!(read_report(&path).unwrap(), failures);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
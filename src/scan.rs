@@ -0,0 +1,385 @@
+//! Which files below a library directory are indexed. Followed symbolic
+//! links enter every directory once, which ends link cycles.
+use crate::database::path_key;
+use crate::is_image;
+use globset::GlobSet;
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+use ignore::Match;
+use std::collections::HashSet;
+use std::fs::{Metadata, ReadDir};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Receiver};
+use std::sync::Arc;
+
+pub const IGNORE_FILE: &str = ".imgfindignore";
+
+/// Folders kept by operating systems and NAS software, compared ignoring case.
+const SYSTEM_FOLDERS: &[&str] = &[
+    "$recycle.bin",
+    "system volume information",
+    "lost+found",
+    "@eadir",
+    "#recycle",
+    "#snapshot",
+    ".trashes",
+    ".spotlight-v100",
+    ".fseventsd",
+];
+
+#[derive(Debug, Clone, Default)]
+pub struct Filter {
+    /// Matched against whole paths.
+    pub exclude: GlobSet,
+    /// Files smaller than this many bytes are skipped.
+    pub min_size: u64,
+    /// Images narrower or lower than this many pixels are skipped.
+    pub min_resolution: u32,
+    /// Whether files and directories whose name starts with a dot are indexed.
+    pub hidden: bool,
+    /// Whether system folders are indexed.
+    pub system_folders: bool,
+    /// Whether symbolic links to files and directories are followed.
+    pub follow_symlinks: bool,
+    /// Whether directories on other file systems than the root are skipped.
+    pub one_file_system: bool,
+}
+
+impl Filter {
+    /// Whether `path`, found while walking a directory, is left out by its
+    /// name, by the exclude patterns or by an ignore file.
+    pub fn skips(&self, ignores: &Ignores, path: &Path, is_dir: bool) -> bool {
+        let name = path
+            .file_name()
+            .map_or_else(String::new, |name| name.to_string_lossy().to_lowercase());
+        (!self.hidden && name.starts_with('.'))
+            || (!self.system_folders && is_dir && SYSTEM_FOLDERS.contains(&name.as_str()))
+            || self.exclude.is_match(path)
+            || ignores.is_ignored(path, is_dir)
+    }
+
+    /// Whether `path` below `root` is left out, checking each directory on
+    /// the way to it as a walk from `root` would.
+    pub fn skips_below(&self, root: &Path, path: &Path, is_dir: bool) -> bool {
+        let Ok(rest) = path.strip_prefix(root) else {
+            return false;
+        };
+        let mut ignores = Ignores::default().enter(root);
+        let mut dir = root.to_path_buf();
+        let mut components = rest.components().peekable();
+        while let Some(component) = components.next() {
+            dir.push(component);
+            let last = components.peek().is_none();
+            if self.skips(&ignores, &dir, is_dir || !last) {
+                return true;
+            }
+            if !last {
+                ignores = ignores.enter(&dir);
+            }
+        }
+        false
+    }
+
+    /// Whether the image at `path` of `size` bytes is smaller than wanted.
+    /// Only the header of the image is read, and an image whose size cannot
+    /// be told is kept.
+    pub fn too_small(&self, path: &Path, size: u64) -> bool {
+        if size < self.min_size {
+            return true;
+        }
+        if self.min_resolution == 0 {
+            return false;
+        }
+        match image::image_dimensions(path) {
+            Ok((width, height)) => width.min(height) < self.min_resolution,
+            Err(_) => false,
+        }
+    }
+}
+
+/// Identifies a directory, to enter each one once when following links.
+#[cfg(unix)]
+type DirId = (u64, u64);
+#[cfg(not(unix))]
+type DirId = PathBuf;
+
+#[cfg(unix)]
+fn dir_id(_: &Path, metadata: &Metadata) -> std::io::Result<DirId> {
+    use std::os::unix::fs::MetadataExt;
+    Ok((metadata.dev(), metadata.ino()))
+}
+
+#[cfg(not(unix))]
+fn dir_id(path: &Path, _: &Metadata) -> std::io::Result<DirId> {
+    path.canonicalize()
+}
+
+/// The file system a path is on: its device on unix, its drive elsewhere.
+#[cfg(unix)]
+type FileSystemId = u64;
+#[cfg(not(unix))]
+type FileSystemId = PathBuf;
+
+#[cfg(unix)]
+fn file_system(_: &Path, metadata: &Metadata) -> std::io::Result<FileSystemId> {
+    use std::os::unix::fs::MetadataExt;
+    Ok(metadata.dev())
+}
+
+#[cfg(not(unix))]
+fn file_system(path: &Path, _: &Metadata) -> std::io::Result<FileSystemId> {
+    let path = path.canonicalize()?;
+    Ok(path.components().take(2).collect())
+}
+
+struct Dir {
+    entries: ReadDir,
+    ignores: Ignores,
+}
+
+/// The images below a directory, found as they are asked for.
+pub struct Walk {
+    filter: Filter,
+    stack: Vec<Dir>,
+    visited: HashSet<DirId>,
+    /// The file system of the root, when staying on it.
+    file_system: Option<FileSystemId>,
+}
+
+impl Walk {
+    /// Starts walking `root`, whose own ignore file is read along with the
+    /// ones already in `ignores`. Only an unreadable `root` is an error;
+    /// directories below it that cannot be read are logged and skipped.
+    pub fn new(root: &Path, filter: &Filter, ignores: &Ignores) -> std::io::Result<Walk> {
+        let metadata = std::fs::metadata(root)?;
+        let file_system = match filter.one_file_system {
+            true => Some(file_system(root, &metadata)?),
+            false => None,
+        };
+        let mut walk = Walk {
+            filter: filter.clone(),
+            stack: Vec::new(),
+            visited: HashSet::new(),
+            file_system,
+        };
+        walk.enter(root, &metadata, ignores)?;
+        Ok(walk)
+    }
+
+    fn enter(&mut self, dir: &Path, metadata: &Metadata, ignores: &Ignores) -> std::io::Result<()> {
+        if self.filter.follow_symlinks && !self.visited.insert(dir_id(dir, metadata)?) {
+            log::debug!(path:% = dir.display(); "skipping directory that was already walked");
+            return Ok(());
+        }
+        if let Some(file_system) = &self.file_system {
+            if file_system != &self::file_system(dir, metadata)? {
+                log::debug!(path:% = dir.display(); "skipping directory on another file system");
+                return Ok(());
+            }
+        }
+        self.stack.push(Dir {
+            entries: std::fs::read_dir(dir)?,
+            ignores: ignores.enter(dir),
+        });
+        Ok(())
+    }
+}
+
+impl Iterator for Walk {
+    type Item = PathBuf;
+
+    fn next(&mut self) -> Option<PathBuf> {
+        loop {
+            let dir = self.stack.last_mut()?;
+            let entry = match dir.entries.next() {
+                Some(Ok(entry)) => entry,
+                Some(Err(e)) => {
+                    log::warn!("skipping unreadable entry: {}", e);
+                    continue;
+                }
+                None => {
+                    self.stack.pop();
+                    continue;
+                }
+            };
+            let path = entry.path();
+            let is_link = entry.file_type().is_ok_and(|t| t.is_symlink());
+            if is_link && !self.filter.follow_symlinks {
+                continue;
+            }
+            let metadata = match std::fs::metadata(&path) {
+                Ok(metadata) => metadata,
+                Err(e) => {
+                    log::warn!(path:% = path.display(); "skipping unreadable entry: {}", e);
+                    continue;
+                }
+            };
+            let is_dir = metadata.is_dir();
+            if self.filter.skips(&dir.ignores, &path, is_dir) {
+                continue;
+            }
+            if is_dir {
+                let ignores = dir.ignores.clone();
+                if let Err(e) = self.enter(&path, &metadata, &ignores) {
+                    log::warn!(path:% = path.display(); "skipping unreadable directory: {}", e);
+                }
+            } else if is_image(&path) {
+                if self.filter.too_small(&path, metadata.len()) {
+                    log::debug!(path:% = path.display(); "skipping small image");
+                    continue;
+                }
+                return Some(path);
+            }
+        }
+    }
+}
+
+/// Images found by walks on another thread, as database keys, so that
+/// indexing can start before the walks are done.
+pub struct Scan {
+    images: Receiver<String>,
+    found: Arc<AtomicUsize>,
+}
+
+impl Scan {
+    pub fn spawn(walks: Vec<Walk>) -> Scan {
+        let (sender, images) = mpsc::channel();
+        let found = Arc::new(AtomicUsize::new(0));
+        let thread_found = found.clone();
+        std::thread::spawn(move || {
+            for path in walks.into_iter().flatten() {
+                let Some(key) = path_key(&path) else {
+                    log::warn!(path:% = path.display(); "skipping path that is not valid unicode");
+                    continue;
+                };
+                thread_found.fetch_add(1, Ordering::Relaxed);
+                if sender.send(key).is_err() {
+                    break;
+                }
+            }
+        });
+        Scan { images, found }
+    }
+
+    /// Images that are already known, such as those of a failure report.
+    pub fn list(images: Vec<String>) -> Scan {
+        let (sender, receiver) = mpsc::channel();
+        let found = Arc::new(AtomicUsize::new(images.len()));
+        for image in images {
+            let _ = sender.send(image);
+        }
+        Scan {
+            images: receiver,
+            found,
+        }
+    }
+
+    /// How many images were found so far.
+    pub fn found(&self) -> usize {
+        self.found.load(Ordering::Relaxed)
+    }
+}
+
+impl Iterator for Scan {
+    type Item = String;
+
+    fn next(&mut self) -> Option<String> {
+        self.images.recv().ok()
+    }
+}
+
+/// The ignore files of the directories a walk is in, outermost first.
+#[derive(Debug, Clone, Default)]
+pub struct Ignores(Vec<Arc<Gitignore>>);
+
+impl Ignores {
+    /// These ignores with the ignore file of `dir` added, if it has one.
+    pub fn enter(&self, dir: &Path) -> Ignores {
+        let file = dir.join(IGNORE_FILE);
+        if !file.is_file() {
+            return self.clone();
+        }
+        let mut builder = GitignoreBuilder::new(dir);
+        if let Some(e) = builder.add(&file) {
+            log::warn!(path:% = file.display(); "invalid ignore file: {}", e);
+        }
+        match builder.build() {
+            Ok(gitignore) => {
+                let mut ignores = self.clone();
+                ignores.0.push(Arc::new(gitignore));
+                ignores
+            }
+            Err(e) => {
+                log::warn!(path:% = file.display(); "invalid ignore file: {}", e);
+                self.clone()
+            }
+        }
+    }
+
+    /// As in git, the innermost file with a matching rule decides, and a
+    /// `!pattern` takes a path back in.
+    pub fn is_ignored(&self, path: &Path, is_dir: bool) -> bool {
+        for gitignore in self.0.iter().rev() {
+            match gitignore.matched_path_or_any_parents(path, is_dir) {
+                Match::Ignore(_) => return true,
+                Match::Whitelist(_) => return false,
+                Match::None => {}
+            }
+        }
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_filter() {
+        let root = std::env::temp_dir().join(format!("imgfind-scan-{}", std::process::id()));
+        std::fs::create_dir_all(root.join("raw")).unwrap();
+        std::fs::write(root.join(IGNORE_FILE), "raw/\n*.png\n!keep.png\n").unwrap();
+        let filter = Filter::default();
+        let ignores = Ignores::default().enter(&root);
+        assert!(filter.skips(&ignores, &root.join("raw"), true));
+        assert!(filter.skips(&ignores, &root.join("a.png"), false));
+        assert!(!filter.skips(&ignores, &root.join("keep.png"), false));
+        assert!(!filter.skips(&ignores, &root.join("a.jpg"), false));
+        assert!(filter.skips(&ignores, &root.join(".git"), true));
+        assert!(filter.skips(&ignores, &root.join("$RECYCLE.BIN"), true));
+        assert!(filter.skips_below(&root, &root.join("raw/a.jpg"), false));
+        assert!(filter.skips_below(&root, &root.join(".cache/a.jpg"), false));
+        assert!(!filter.skips_below(&root, &root.join("2020/a.jpg"), false));
+
+        let filter = Filter {
+            hidden: true,
+            min_size: 100,
+            ..Default::default()
+        };
+        assert!(!filter.skips(&Ignores::default(), &root.join(".git"), true));
+        assert!(filter.too_small(&root.join("a.jpg"), 99));
+        assert!(!filter.too_small(&root.join("a.jpg"), 100));
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_walk_symlink_cycle() {
+        let root = std::env::temp_dir().join(format!("imgfind-walk-{}", std::process::id()));
+        std::fs::create_dir_all(root.join("a")).unwrap();
+        std::fs::write(root.join("a/1.jpg"), b"").unwrap();
+        std::os::unix::fs::symlink(&root, root.join("a/loop")).unwrap();
+        let walk = |follow_symlinks| {
+            let filter = Filter {
+                follow_symlinks,
+                ..Default::default()
+            };
+            Walk::new(&root, &filter, &Ignores::default())
+                .unwrap()
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(walk(false), [root.join("a/1.jpg")]);
+        assert_eq!(walk(true), [root.join("a/1.jpg")]);
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,102 @@
+//! Zero-shot tagging: every image is classified against a user supplied list
+//! of labels, the same way CLIP does zero-shot classification.
+use crate::database::Database;
+use crate::error::{Error, Result};
+use crate::{dot_product, encode_text, model};
+use tokenizers::tokenizer::Tokenizer;
+
+fn softmax(logits: &[f32]) -> Vec<f32> {
+    let max = logits.iter().copied().fold(f32::MIN, f32::max);
+    let exp: Vec<f32> = logits.iter().map(|x| (x - max).exp()).collect();
+    let sum: f32 = exp.iter().sum();
+    exp.iter().map(|x| x / sum).collect()
+}
+
+/// Splits a comma separated tag list, dropping empty entries.
+pub fn parse_tags(list: &str) -> Vec<String> {
+    list.split(',')
+        .map(str::trim)
+        .filter(|tag| !tag.is_empty())
+        .map(str::to_string)
+        .collect()
+}
+
+/// Replaces the tags of an image from an earlier run with `labels` by those
+/// whose probability is at least `threshold`, keeping tags of other labels.
+fn merge_tags(
+    tags: &mut Vec<(String, f32)>,
+    labels: &[String],
+    probabilities: &[f32],
+    threshold: f32,
+) {
+    tags.retain(|(tag, _)| !labels.contains(tag));
+    for (label, &p) in labels.iter().zip(probabilities) {
+        if p >= threshold {
+            tags.push((label.clone(), p));
+        }
+    }
+}
+
+/// Tags every image with the labels whose probability is at least
+/// `threshold`, where probabilities are a softmax over `labels` of the
+/// similarities multiplied by CLIP's `logit_scale`. Tags from runs with
+/// other labels are kept. A softmax over one label is always 1, so at least
+/// two are needed.
+pub fn tag_database(
+    database: &mut Database,
+    model: &model::ClipTextTransformer,
+    tokenizer: &Tokenizer,
+    logit_scale: f32,
+    labels: &[String],
+    threshold: f32,
+) -> Result<()> {
+    if labels.len() < 2 {
+        return Err(Error::Config(
+            "at least two tags are needed to choose between".to_string(),
+        ));
+    }
+    if !crate::dupes::valid_threshold(threshold) {
+        return Err(Error::Config(format!(
+            "threshold {} is not in (0, 1]",
+            threshold
+        )));
+    }
+    let prompts = labels
+        .iter()
+        .map(|label| encode_text(model, tokenizer, &format!("a photo of {}", label)))
+        .collect::<Result<Vec<_>>>()?;
+    for (path, embedding) in database.embeddings.iter() {
+        let logits: Vec<f32> = prompts
+            .iter()
+            .map(|prompt| logit_scale * dot_product(embedding, prompt))
+            .collect();
+        let tags = database.tags.entry(path.clone()).or_default();
+        merge_tags(tags, labels, &softmax(&logits), threshold);
+    }
+    database.tags.retain(|_, tags| !tags.is_empty());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_softmax() {
+        let p = softmax(&[1.0, 2.0, 3.0]);
+        assert!((p.iter().sum::<f32>() - 1.0).abs() < 1e-6);
+        assert!(p[2] > p[1] && p[1] > p[0]);
+        assert_eq!(
+            parse_tags("cat, receipt,,screenshot "),
+            ["cat", "receipt", "screenshot"]
+        );
+
+        let mut tags = vec![("receipt".to_string(), 0.9), ("cat".to_string(), 0.4)];
+        let labels = ["cat".to_string(), "dog".to_string()];
+        merge_tags(&mut tags, &labels, &[0.2, 0.8], 0.3);
+        assert_eq!(
+            tags,
+            [("receipt".to_string(), 0.9), ("dog".to_string(), 0.8)]
+        );
+    }
+}
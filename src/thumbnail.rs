@@ -0,0 +1,206 @@
+//! Cache files are named after the content hash of the original, so renamed
+//! files share one thumbnail and an edited file never gets a stale one.
+use crate::load_image;
+use std::collections::HashMap;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+pub const THUMBNAIL_DIR_EXTENSION: &str = "thumbnails";
+/// Thumbnail edge lengths that are generated; requests are rounded up to one
+/// of these so clients cannot fill the cache with arbitrary sizes.
+pub const SIZES: [u32; 4] = [128, 256, 512, 1024];
+pub const DEFAULT_SIZE: u32 = 256;
+/// The sizes the web UI asks for at device pixel ratios of one and two,
+/// created ahead of time when indexing with thumbnails.
+pub const PREGENERATED: [u32; 2] = [256, 512];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Jpeg,
+    #[cfg(feature = "webp")]
+    WebP,
+}
+
+impl Format {
+    /// The format served to browsers that accept it, which all current ones do.
+    #[cfg(feature = "webp")]
+    pub const PREFERRED: Format = Format::WebP;
+    #[cfg(not(feature = "webp"))]
+    pub const PREFERRED: Format = Format::Jpeg;
+
+    fn extension(self) -> &'static str {
+        match self {
+            Format::Jpeg => "jpg",
+            #[cfg(feature = "webp")]
+            Format::WebP => "webp",
+        }
+    }
+
+    pub fn content_type(self) -> &'static str {
+        match self {
+            Format::Jpeg => "image/jpeg",
+            #[cfg(feature = "webp")]
+            Format::WebP => "image/webp",
+        }
+    }
+}
+
+pub fn round_size(size: u32) -> u32 {
+    SIZES
+        .iter()
+        .copied()
+        .find(|&s| s >= size)
+        .unwrap_or(SIZES[SIZES.len() - 1])
+}
+
+/// 64-bit FNV-1a hash of the file content.
+pub fn content_hash(path: &str) -> std::io::Result<u64> {
+    let mut file = std::fs::File::open(crate::database::key_path(path))?;
+    let mut buffer = vec![0u8; 64 * 1024];
+    let mut hash = 0xcbf2_9ce4_8422_2325u64;
+    loop {
+        let len = file.read(&mut buffer)?;
+        if len == 0 {
+            break;
+        }
+        for &byte in &buffer[..len] {
+            hash = (hash ^ byte as u64).wrapping_mul(0x0100_0000_01b3);
+        }
+    }
+    Ok(hash)
+}
+
+/// Remembers content hashes by modification time and length, so serving a
+/// cached thumbnail does not read the whole original every time.
+#[derive(Default)]
+pub struct HashCache {
+    hashes: Mutex<HashMap<String, (SystemTime, u64, u64)>>,
+}
+
+impl HashCache {
+    pub fn get(&self, path: &str) -> std::io::Result<u64> {
+        let metadata = std::fs::metadata(crate::database::key_path(path))?;
+        let key = (metadata.modified()?, metadata.len());
+        if let Some(&(modified, len, hash)) = self.hashes.lock().unwrap().get(path) {
+            if (modified, len) == key {
+                return Ok(hash);
+            }
+        }
+        let hash = content_hash(path)?;
+        self.hashes
+            .lock()
+            .unwrap()
+            .insert(path.to_string(), (key.0, key.1, hash));
+        Ok(hash)
+    }
+}
+
+/// The thumbnail cache of the collection whose database is at `database`,
+/// e.g. `photos.thumbnails` for `photos.bin`, so collections whose databases
+/// share a directory do not share a cache.
+pub fn cache_dir(database: &Path) -> PathBuf {
+    database.with_extension(THUMBNAIL_DIR_EXTENSION)
+}
+
+pub fn cache_path(dir: &Path, hash: u64, size: u32, format: Format) -> PathBuf {
+    dir.join(format!("{:016x}-{}.{}", hash, size, format.extension()))
+}
+
+fn encode(img: &image::DynamicImage, size: u32, format: Format) -> image::ImageResult<Vec<u8>> {
+    let thumbnail = img.thumbnail(size, size).to_rgb8();
+    let mut buffer = std::io::Cursor::new(Vec::new());
+    match format {
+        Format::Jpeg => thumbnail.write_to(&mut buffer, image::ImageOutputFormat::Jpeg(85))?,
+        #[cfg(feature = "webp")]
+        Format::WebP => {
+            use image::codecs::webp::{WebPEncoder, WebPQuality};
+            WebPEncoder::new_with_quality(&mut buffer, WebPQuality::lossy(80)).encode(
+                thumbnail.as_raw(),
+                thumbnail.width(),
+                thumbnail.height(),
+                image::ColorType::Rgb8,
+            )?
+        }
+    }
+    Ok(buffer.into_inner())
+}
+
+/// Writes the thumbnail of an already decoded image into the cache at `dir`.
+pub fn store(
+    dir: &Path,
+    hash: u64,
+    img: &image::DynamicImage,
+    size: u32,
+    format: Format,
+) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    let content = encode(img, size, format)?;
+    std::fs::create_dir_all(dir)?;
+    // write then rename, so a concurrent reader never sees half a file; each
+    // writer has its own partial file, as two may create the same thumbnail
+    static WRITES: AtomicUsize = AtomicUsize::new(0);
+    let path = cache_path(dir, hash, size, format);
+    let partial = path.with_extension(format!(
+        "{}.{}-{}.part",
+        format.extension(),
+        std::process::id(),
+        WRITES.fetch_add(1, Ordering::Relaxed)
+    ));
+    std::fs::write(&partial, &content)?;
+    std::fs::rename(&partial, &path)?;
+    Ok(content)
+}
+
+/// Writes the thumbnails the web UI will ask for of an already decoded image.
+pub fn store_all(
+    dir: &Path,
+    hash: u64,
+    img: &image::DynamicImage,
+) -> Result<(), Box<dyn std::error::Error>> {
+    for size in PREGENERATED {
+        store(dir, hash, img, size, Format::PREFERRED)?;
+    }
+    Ok(())
+}
+
+/// Returns the cached thumbnail, generating it first if needed.
+pub fn get_or_create(
+    dir: &Path,
+    path: &str,
+    hash: u64,
+    size: u32,
+    format: Format,
+) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    match std::fs::read(cache_path(dir, hash, size, format)) {
+        Ok(content) => Ok(content),
+        Err(_) => store(dir, hash, &load_image(path)?, size, format),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_size() {
+        assert_eq!(round_size(1), 128);
+        assert_eq!(round_size(200), 256);
+        assert_eq!(round_size(256), 256);
+        assert_eq!(round_size(5000), 1024);
+        assert_eq!(
+            cache_dir(Path::new("/data/photos.bin")),
+            Path::new("/data/photos.thumbnails")
+        );
+        assert_ne!(
+            cache_dir(Path::new("/data/work.bin")),
+            cache_dir(Path::new("/data/photos.bin"))
+        );
+        // what the web UI asks for at 200 * devicePixelRatio
+        for ratio in [1.0f32, 1.25, 1.5, 2.0, 2.5] {
+            let size = round_size((200.0 * ratio).round() as u32);
+            assert!(PREGENERATED.contains(&size), "{}", size);
+        }
+    }
+}
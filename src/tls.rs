@@ -0,0 +1,30 @@
+//! TLS for `serve`, layered over each accepted connection with rustls.
+use rustls::pki_types::CertificateDer;
+use rustls::{ServerConfig, ServerConnection, StreamOwned};
+use std::io::BufReader;
+use std::net::TcpStream;
+use std::sync::Arc;
+
+type Error = Box<dyn std::error::Error + Send + Sync>;
+
+/// Loads a PEM certificate chain and private key.
+pub fn load_config(cert: &str, key: &str) -> Result<Arc<ServerConfig>, Error> {
+    let mut reader = BufReader::new(std::fs::File::open(cert)?);
+    let certs = rustls_pemfile::certs(&mut reader).collect::<Result<Vec<CertificateDer>, _>>()?;
+    let mut reader = BufReader::new(std::fs::File::open(key)?);
+    let key = rustls_pemfile::private_key(&mut reader)?
+        .ok_or_else(|| format!("no private key found in {}", key))?;
+    let config = ServerConfig::builder()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)?;
+    Ok(Arc::new(config))
+}
+
+/// Wraps a connection; the handshake happens on its first read.
+pub fn accept(
+    config: &Arc<ServerConfig>,
+    stream: TcpStream,
+) -> std::io::Result<StreamOwned<ServerConnection, TcpStream>> {
+    let connection = ServerConnection::new(config.clone()).map_err(std::io::Error::other)?;
+    Ok(StreamOwned::new(connection, stream))
+}
@@ -0,0 +1,232 @@
+use crate::database::Root;
+use crate::error::{Error, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+const USERDATA_FILE: &str = "userdata.json";
+/// Version 1 stored favourites as bare paths.
+const USERDATA_VERSION: u32 = 2;
+const HISTORY_LEN: usize = 100;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct HistoryEntry {
+    pub query: String,
+    /// Seconds since the Unix epoch.
+    pub time: u64,
+}
+
+/// `library` is unknown for images starred in version 1.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(from = "StoredFavourite")]
+pub struct Favourite {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub library: Option<String>,
+    pub path: String,
+}
+
+impl Favourite {
+    fn is(&self, library: &str, path: &str) -> bool {
+        self.path == path && self.library.as_deref().is_none_or(|l| l == library)
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum StoredFavourite {
+    Path(String),
+    Favourite {
+        library: Option<String>,
+        path: String,
+    },
+}
+
+impl From<StoredFavourite> for Favourite {
+    fn from(stored: StoredFavourite) -> Self {
+        match stored {
+            StoredFavourite::Path(path) => Self {
+                library: None,
+                path,
+            },
+            StoredFavourite::Favourite { library, path } => Self { library, path },
+        }
+    }
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct UserStore {
+    #[serde(default)]
+    pub history: Vec<HistoryEntry>,
+    #[serde(default)]
+    pub saved: BTreeMap<String, String>,
+    /// By list name.
+    #[serde(default)]
+    pub favourites: BTreeMap<String, Vec<Favourite>>,
+}
+
+impl UserStore {
+    pub fn record_search(&mut self, query: &str, time: u64) {
+        self.history.retain(|entry| entry.query != query);
+        self.history.insert(
+            0,
+            HistoryEntry {
+                query: query.to_string(),
+                time,
+            },
+        );
+        self.history.truncate(HISTORY_LEN);
+    }
+
+    /// Returns whether the image was new to the list.
+    pub fn star(&mut self, list: &str, library: &str, path: &str) -> bool {
+        let favourites = self.favourites.entry(list.to_string()).or_default();
+        if favourites.iter().any(|f| f.is(library, path)) {
+            return false;
+        }
+        favourites.push(Favourite {
+            library: Some(library.to_string()),
+            path: path.to_string(),
+        });
+        true
+    }
+
+    /// A list left empty is removed.
+    pub fn unstar(&mut self, list: &str, library: &str, path: &str) -> bool {
+        let Some(favourites) = self.favourites.get_mut(list) else {
+            return false;
+        };
+        let len = favourites.len();
+        favourites.retain(|f| !f.is(library, path));
+        let removed = favourites.len() != len;
+        if favourites.is_empty() {
+            self.favourites.remove(list);
+        }
+        removed
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct UserData {
+    version: u32,
+    /// The empty name is used without `--user`.
+    pub users: BTreeMap<String, UserStore>,
+    #[serde(skip)]
+    path: PathBuf,
+}
+
+impl UserData {
+    pub fn user(&mut self, name: &str) -> &mut UserStore {
+        self.users.entry(name.to_string()).or_default()
+    }
+
+    /// Follows a remapped root of `library` in every favourites list.
+    pub fn rebase(&mut self, library: &str, old: &Root, new: &Root) -> bool {
+        let mut moved = false;
+        for store in self.users.values_mut() {
+            for favourite in store.favourites.values_mut().flatten() {
+                if favourite.library.as_deref().is_some_and(|l| l != library) {
+                    continue;
+                }
+                if let Some(rebased) = old.rebase(&favourite.path, new) {
+                    favourite.path = rebased;
+                    moved = true;
+                }
+            }
+        }
+        moved
+    }
+}
+
+pub fn load_userdata(database: &Path) -> Result<UserData> {
+    let path = database.with_file_name(USERDATA_FILE);
+    let userdata = match std::fs::read(&path) {
+        Ok(bytes) => serde_json::from_slice(&bytes).map_err(|e| Error::Database {
+            path: path.clone(),
+            message: e.to_string(),
+        })?,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => UserData {
+            version: USERDATA_VERSION,
+            users: BTreeMap::new(),
+            path: PathBuf::new(),
+        },
+        Err(e) => return Err(Error::io(path, e)),
+    };
+    Ok(UserData {
+        version: USERDATA_VERSION,
+        path,
+        ..userdata
+    })
+}
+
+pub fn save_userdata(userdata: &UserData) -> std::io::Result<()> {
+    // write then rename, so a crash never leaves half a file behind
+    let mut partial = userdata.path.clone().into_os_string();
+    partial.push(".part");
+    std::fs::write(&partial, serde_json::to_vec_pretty(userdata)?)?;
+    std::fs::rename(&partial, &userdata.path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_user_store() {
+        let mut store = UserStore::default();
+        store.record_search("cat", 1);
+        store.record_search("dog", 2);
+        store.record_search("cat", 3);
+        let queries: Vec<&str> = store.history.iter().map(|e| e.query.as_str()).collect();
+        assert_eq!(queries, ["cat", "dog"]);
+
+        assert!(store.star("pets", "default", "a.jpg"));
+        assert!(!store.star("pets", "default", "a.jpg"));
+        assert!(store.star("pets", "work", "a.jpg"));
+        assert!(store.unstar("pets", "default", "a.jpg"));
+        assert!(store.unstar("pets", "work", "a.jpg"));
+        assert!(!store.favourites.contains_key("pets"));
+
+        // version 1 kept bare paths, which match an image of any library
+        let store: UserStore =
+            serde_json::from_str(r#"{"favourites": {"pets": ["a.jpg"]}}"#).unwrap();
+        assert_eq!(store.favourites["pets"][0].library, None);
+        assert!(store.favourites["pets"][0].is("work", "a.jpg"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_rebase() {
+        let root = |location: &str| Root {
+            name: "photos".to_string(),
+            location: location.to_string(),
+        };
+        let mut userdata = UserData {
+            version: USERDATA_VERSION,
+            users: BTreeMap::new(),
+            path: PathBuf::new(),
+        };
+        // two lists holding images of the library "default" and one image of
+        // the library "work", which has a root at the same place
+        userdata.user("").star("pets", "default", "/old/cat.jpg");
+        userdata.user("").star("pets", "work", "/old/report.jpg");
+        userdata
+            .user("alice")
+            .star("best", "default", "/old/a/dog.jpg");
+        userdata
+            .user("alice")
+            .star("best", "default", "/elsewhere/b.jpg");
+        assert!(userdata.rebase("default", &root("/old"), &root("/new")));
+        let paths = |user: &str, list: &str| -> Vec<String> {
+            userdata.users[user].favourites[list]
+                .iter()
+                .map(|f| f.path.clone())
+                .collect()
+        };
+        assert_eq!(paths("", "pets"), ["/new/cat.jpg", "/old/report.jpg"]);
+        assert_eq!(
+            paths("alice", "best"),
+            ["/new/a/dog.jpg", "/elsewhere/b.jpg"]
+        );
+        assert!(!userdata.rebase("default", &root("/old"), &root("/new")));
+    }
+}
@@ -0,0 +1,246 @@
+use crate::database::{key_path, path_key, save_database, Database};
+use crate::scan::{Filter, Ignores};
+use crate::{get_images_below, image_feature, is_image, log_failure, model};
+use notify::{Event, EventKind, RecommendedWatcher, RecursiveMode, Watcher};
+use std::collections::BTreeSet;
+use std::path::{Path, PathBuf, MAIN_SEPARATOR};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError};
+use std::sync::RwLock;
+use std::time::{Duration, Instant};
+
+/// A batch is indexed once no event arrived for this long, so that files
+/// still being written are not read halfway...
+const QUIET: Duration = Duration::from_secs(2);
+/// ...or at the latest this long after its first event.
+const MAX_DELAY: Duration = Duration::from_secs(30);
+
+struct Root {
+    given: PathBuf,
+    canonical: PathBuf,
+}
+
+impl Root {
+    /// The key `add` would have stored for a path the watcher reports.
+    fn database_path(&self, path: &Path) -> Option<String> {
+        let rest = path.strip_prefix(&self.canonical).ok()?;
+        let path = if rest.as_os_str().is_empty() {
+            self.given.clone()
+        } else {
+            self.given.join(rest)
+        };
+        path_key(&path)
+    }
+}
+
+pub struct Watch {
+    roots: Vec<Root>,
+    filter: Filter,
+    thumbnails: Option<PathBuf>,
+    // kept alive for as long as events are received
+    _watcher: RecommendedWatcher,
+    events: Receiver<notify::Result<Event>>,
+}
+
+impl Watch {
+    pub fn new(
+        roots: &[String],
+        filter: Filter,
+        thumbnails: Option<PathBuf>,
+    ) -> notify::Result<Self> {
+        let (tx, events) = mpsc::channel();
+        let mut watcher = notify::recommended_watcher(tx)?;
+        let mut watched = Vec::new();
+        for root in roots {
+            let given = key_path(root);
+            let canonical = given.canonicalize()?;
+            watcher.watch(&canonical, RecursiveMode::Recursive)?;
+            watched.push(Root { given, canonical });
+        }
+        Ok(Self {
+            roots: watched,
+            filter,
+            thumbnails,
+            _watcher: watcher,
+            events,
+        })
+    }
+
+    fn collect(&self, event: notify::Result<Event>, pending: &mut BTreeSet<String>) {
+        match event {
+            Ok(event) if matches!(event.kind, EventKind::Access(_)) => {}
+            Ok(event) => pending.extend(
+                event
+                    .paths
+                    .iter()
+                    .filter_map(|path| self.roots.iter().find_map(|root| root.database_path(path))),
+            ),
+            Err(e) => log::warn!("watch error: {}", e),
+        }
+    }
+
+    /// Syncs the roots first, to catch up on changes made while nothing was
+    /// watching.
+    pub fn run(&self, database: &RwLock<Database>, model: &model::ClipVisionTransformer) {
+        let mut pending: BTreeSet<String> = self
+            .roots
+            .iter()
+            .filter_map(|root| path_key(&root.given))
+            .collect();
+        loop {
+            let (mut added, mut removed) = (0, 0);
+            for path in std::mem::take(&mut pending) {
+                let root = self
+                    .roots
+                    .iter()
+                    .find(|root| key_path(&path).starts_with(&root.given));
+                let Some(root) = root else {
+                    continue;
+                };
+                let (a, r) = sync(
+                    database,
+                    model,
+                    &self.filter,
+                    self.thumbnails.as_deref(),
+                    &root.given,
+                    &path,
+                );
+                added += a;
+                removed += r;
+            }
+            if added + removed > 0 {
+                log::info!(added, removed; "watch updated the index");
+                if let Err(e) = save_database(&database.read().unwrap()) {
+                    log::error!("failed to save database: {}", e);
+                }
+            }
+
+            let Ok(event) = self.events.recv() else {
+                return;
+            };
+            self.collect(event, &mut pending);
+            let first = Instant::now();
+            while first.elapsed() < MAX_DELAY {
+                match self.events.recv_timeout(QUIET) {
+                    Ok(event) => self.collect(event, &mut pending),
+                    Err(RecvTimeoutError::Timeout) => break,
+                    Err(RecvTimeoutError::Disconnected) => return,
+                }
+            }
+        }
+    }
+}
+
+/// Returns how many images were added and removed.
+fn sync(
+    database: &RwLock<Database>,
+    model: &model::ClipVisionTransformer,
+    filter: &Filter,
+    thumbnails: Option<&Path>,
+    root: &Path,
+    path: &str,
+) -> (usize, usize) {
+    let file = &key_path(path);
+    let images = if filter.skips_below(root, file, file.is_dir()) {
+        Vec::new()
+    } else if file.is_dir() {
+        let found =
+            get_images_below(path, filter, &ignores_above(root, file)).unwrap_or_else(|e| {
+                log::warn!("{}", e);
+                Vec::new()
+            });
+        // looked up after the walk, so writers are not held up by it
+        let known = database.read().unwrap();
+        found
+            .into_iter()
+            .filter(|image| !known.embeddings.contains_key(image))
+            .collect()
+    } else if file.is_file() && is_image(file) {
+        let size = file.metadata().map_or(u64::MAX, |m| m.len());
+        if filter.too_small(file, size) {
+            Vec::new()
+        } else {
+            vec![path.to_string()]
+        }
+    } else {
+        Vec::new()
+    };
+    let mut added = 0;
+    for image in images {
+        // embedded without the lock, so searches are not held up
+        match image_feature(model, &image, thumbnails) {
+            Ok(feature) => {
+                database.write().unwrap().insert(image, feature);
+                added += 1;
+            }
+            Err(e) => log_failure(&image, &e),
+        }
+    }
+    (added, remove_missing(database, path))
+}
+
+/// The ignore files from `root` down to the parent of `path`.
+fn ignores_above(root: &Path, path: &Path) -> Ignores {
+    let mut ignores = Ignores::default();
+    let Some(parent) = path.parent().filter(|parent| parent.starts_with(root)) else {
+        return ignores;
+    };
+    let mut dir = root.to_path_buf();
+    ignores = ignores.enter(&dir);
+    for component in parent.strip_prefix(root).unwrap().components() {
+        dir.push(component);
+        ignores = ignores.enter(&dir);
+    }
+    ignores
+}
+
+/// Removes `path` and everything indexed below it that no longer exists.
+fn remove_missing(database: &RwLock<Database>, path: &str) -> usize {
+    let prefix = format!(
+        "{}{}",
+        path.trim_end_matches(MAIN_SEPARATOR),
+        MAIN_SEPARATOR
+    );
+    let indexed: Vec<String> = database
+        .read()
+        .unwrap()
+        .embeddings
+        .keys()
+        .filter(|image| *image == path || image.starts_with(&prefix))
+        .cloned()
+        .collect();
+    let missing: Vec<String> = indexed
+        .into_iter()
+        .filter(|image| !key_path(image).exists())
+        .collect();
+    let mut database = database.write().unwrap();
+    for image in missing.iter() {
+        database.remove(image);
+    }
+    missing.len()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_database_path() {
+        let root = Root {
+            given: PathBuf::from("photos"),
+            canonical: PathBuf::from("/home/me/photos"),
+        };
+        let path = |p: &str| root.database_path(Path::new(p));
+        assert_eq!(
+            path("/home/me/photos/2023/a.jpg"),
+            Some(
+                Path::new("photos")
+                    .join("2023")
+                    .join("a.jpg")
+                    .to_string_lossy()
+                    .to_string()
+            )
+        );
+        assert_eq!(path("/home/me/photos"), Some("photos".to_string()));
+        assert_eq!(path("/home/me/photos2/a.jpg"), None);
+    }
+}